@@ -12,80 +12,358 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::cell::RefCell;
+use core::{
+    cell::{Cell, RefCell, RefMut},
+    future::Future,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use crate::virtio::{Error as VirtioError, VirtioTransport};
+use crate::{
+    mem::MemoryRegion,
+    virtio::{
+        fill_indirect_table, Desc, DescSpec, Error as VirtioError, VirtioTransport, Virtqueue,
+        VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_STATUS_DEVICE_NEEDS_RESET,
+        VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FAILED, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_WRITE,
+    },
+};
 
 const QUEUE_SIZE: usize = 16;
 
-#[repr(C)]
-#[repr(align(16))]
-#[derive(Default)]
-/// A virtio qeueue entry descriptor
-struct Desc {
-    addr: u64,
-    length: u32,
-    flags: u16,
-    next: u16,
-}
+// Split virtqueues must be a power of two in size
+const _: () = assert!(QUEUE_SIZE.is_power_of_two());
 
-#[repr(C)]
-#[repr(align(2))]
-#[derive(Default)]
-/// The virtio available ring
-struct AvailRing {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE],
-}
+// Descriptors in the table used for requests that do not fit in the queue,
+// must not be smaller than the queue
+const INDIRECT_SIZE: usize = 32;
 
-#[repr(C)]
-#[repr(align(4))]
-#[derive(Default)]
-/// The virtio used ring
-struct UsedRing {
-    flags: u16,
-    idx: u16,
-    ring: [UsedElem; QUEUE_SIZE],
-}
+// Request queues used when the device supports several, each has its own
+// rings and requests
+const MAX_QUEUES: usize = 2;
 
-#[repr(C)]
-#[derive(Default)]
-/// A single element in the used ring
-struct UsedElem {
-    id: u32,
-    len: u32,
-}
+/// Feature bits that may be negotiated with the device
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_GEOMETRY: u64 = 1 << 4;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+pub const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
+pub const VIRTIO_BLK_F_MQ: u64 = 1 << 12;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+// Number of times the used ring is polled before a request is considered to
+// have timed out. This is several seconds on current hardware.
+const DEFAULT_TIMEOUT: u64 = 1_000_000_000;
+
+// Spins between attempts at a read that failed with an I/O error, giving a
+// briefly stalled backend time to recover
+const READ_RETRY_DELAY: u64 = 100_000;
 
 #[repr(C)]
 #[repr(align(64))]
 /// Device driver for virtio block over any transport
 pub struct VirtioBlockDevice<'a> {
     transport: &'a mut dyn VirtioTransport,
-    state: RefCell<DriverState>,
-    read_only: bool,
+    // Only the first num_queues are set up
+    queues: [RefCell<DriverState>; MAX_QUEUES],
+    num_queues: usize,
+    features: u64,
+    capacity: u64,
+    block_size: u32,
+    // Limits on the number of data segments in a request and their size
+    max_segments: u32,
+    max_segment_size: u32,
+    timeout: u64,
+    // Further attempts at a read that fails with an I/O error
+    read_retries: u32,
+    // Upper bound on the queue size and features the device must offer,
+    // set through the builder
+    max_queue: usize,
+    required_features: u64,
+    scratch: RefCell<SectorBuf>,
+    // Set when the device was reset after a request timed out, until the
+    // next init()
+    stopped: Cell<bool>,
+}
+
+/// Options for a `VirtioBlockDevice` that take effect when it is
+/// initialised, see `VirtioBlockDevice::builder()`
+pub struct VirtioBlockDeviceBuilder<'a> {
+    transport: &'a mut dyn VirtioTransport,
+    timeout: u64,
+    max_queue: usize,
+    required_features: u64,
+}
+
+#[allow(dead_code)]
+impl<'a> VirtioBlockDeviceBuilder<'a> {
+    /// Number of times the device is polled for the completion of a request
+    /// before giving up with `Error::RequestTimeout`
+    pub fn timeout(mut self, iterations: u64) -> Self {
+        self.timeout = iterations;
+        self
+    }
+
+    /// Use at most `size` entries of the queue, rounded down to a power of two
+    pub fn max_queue(mut self, size: usize) -> Self {
+        self.max_queue = size;
+        self
+    }
+
+    /// Fail initialisation unless all of the given feature bits are
+    /// negotiated
+    pub fn require_features(mut self, features: u64) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    /// Create the device and initialise it with the chosen options
+    pub fn init(self) -> Result<VirtioBlockDevice<'a>, VirtioError> {
+        let mut device = VirtioBlockDevice::new(self.transport);
+        device.timeout = self.timeout;
+        device.max_queue = self.max_queue;
+        device.required_features = self.required_features;
+        device.init()?;
+        Ok(device)
+    }
 }
 
 #[repr(C)]
 #[repr(align(64))]
 #[derive(Default)]
 struct DriverState {
-    descriptors: [Desc; QUEUE_SIZE],
-    avail: AvailRing,
-    used: UsedRing,
-    next_head: usize,
+    queue: Virtqueue<QUEUE_SIZE>,
+    // Indexed by the head descriptor of the request's chain
+    requests: [InflightRequest; QUEUE_SIZE],
+    // Table for a single request with more descriptors than are free, the
+    // queue then only holds one descriptor pointing at it
+    indirect: [Desc; INDIRECT_SIZE],
+    indirect_in_use: bool,
+    stats: BlockStats,
+}
+
+impl DriverState {
+    // Mark the requests whose descriptor chains have been returned by the
+    // device as complete, returning whether there were any
+    fn process_used(&mut self) -> bool {
+        let mut any = false;
+        while let Some((head, len)) = self.queue.reclaim() {
+            if let Some(request) = self.requests.get_mut(usize::from(head)) {
+                request.complete = true;
+                request.written = len;
+            }
+            any = true;
+        }
+        any
+    }
+
+    // Link every descriptor of the queue into the free list
+    fn reset_free_list(&mut self) {
+        self.queue.reset_free_list();
+        self.indirect_in_use = false;
+    }
+
+    // Return a chain that the device has finished with to the free list
+    fn free_chain(&mut self, head: usize) {
+        let head = head as u16;
+        if self.queue.descriptor(head).flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            self.indirect_in_use = false;
+        }
+        self.queue.free_chain(head);
+    }
+}
+
+/// Status values reported by the device for a request
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Status value used to mark a footer that the device has not yet written
+const VIRTIO_BLK_S_INVALID: u8 = 0xff;
+
+#[repr(C)]
+#[derive(Default)]
+/// Device owned storage for a request that has been submitted. The header
+/// and footer descriptors always point here rather than at the caller's stack
+/// so they remain valid for as long as the request is in flight.
+struct InflightRequest {
+    header: BlockRequestHeader,
+    segment: DiscardWriteZeroesSegment,
+    footer: BlockRequestFooter,
+    complete: bool,
+    // Bytes of data in the chain, those the device is expected to write into
+    // it and the number it reported having written
+    length: u32,
+    expected: u32,
+    written: u32,
+}
+
+bitflags::bitflags! {
+    /// Operations that can be issued to a device given the negotiated features
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SupportedOps: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const FLUSH = 1 << 2;
+        const DISCARD = 1 << 3;
+        const WRITE_ZEROES = 1 << 4;
+        const GET_ID = 1 << 5;
+    }
+}
+
+/// Running totals of the requests handled by a device
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BlockStats {
+    pub submitted: u64,
+    pub completed: u64,
+    /// Data bytes moved by requests that completed successfully
+    pub bytes: u64,
+}
+
+/// Whether the device caches writes until they are flushed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteThrough = 0,
+    WriteBack = 1,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+/// I/O alignment hints, sizes are in logical blocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Logical blocks per physical block as a power of two
+    pub physical_block_exp: u8,
+    /// Offset of the first aligned logical block
+    pub alignment_offset: u8,
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+}
+
+/// Limits on discard requests, in 512-byte sectors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DiscardLimits {
+    /// Largest number of sectors in a single segment
+    pub max_sectors: u32,
+    /// Largest number of segments in a single request
+    pub max_segments: u32,
+    pub sector_alignment: u32,
+}
+
+/// Handle for a request that has been submitted to the device. The buffer
+/// used by the request remains borrowed until the request is waited for.
+#[must_use]
+pub struct RequestToken<'b> {
+    queue: usize,
+    head: usize,
+    _data: PhantomData<&'b mut [u8]>,
+}
+
+/// Future completing with the result of a submitted read, returned by
+/// [`VirtioBlockDevice::wait_async`]. There are no interrupts to wake the
+/// task so every poll checks the used ring and, while the read is still in
+/// flight, asks to be polled again. Dropping it before then blocks until
+/// the read has completed.
+#[must_use]
+pub struct ReadFuture<'d, 'a, 'b> {
+    device: &'d VirtioBlockDevice<'a>,
+    token: Option<RequestToken<'b>>,
+}
+
+impl<'d, 'a, 'b> Future for ReadFuture<'d, 'a, 'b> {
+    type Output = BlockResult<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let token = self
+            .token
+            .as_ref()
+            .expect("ReadFuture polled after completion");
+        match self.device.poll(token) {
+            Ok(false) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            result => {
+                self.token = None;
+                Poll::Ready(result.map(|_| ()))
+            }
+        }
+    }
+}
+
+// The device keeps writing into the buffer until the read completes, so a
+// future dropped early waits for it before the borrow ends
+impl<'d, 'a, 'b> Drop for ReadFuture<'d, 'a, 'b> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            let _ = self.device.wait(token);
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Error {
     BlockIO,
     NoDataBuf,
     InvalidDataBufSize,
+    SectorOutOfRange,
+    QueueFull,
+    RequestTimeout,
+    #[cfg_attr(not(feature = "block-write"), allow(dead_code))]
+    ReadOnlyDevice,
+    DeviceNeedsReset,
+    #[cfg_attr(not(feature = "block-write"), allow(dead_code))]
+    WriteVerifyMismatch,
+    ShortTransfer,
+    SelfTestFailed,
+    RequestTooLarge,
+    InvalidQueue,
+    /// Status byte outside of the values defined by the specification
+    UnknownBlockStatus(u8),
 
     BlockNotSupported,
 }
 
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::UnknownBlockStatus(status) => {
+                return write!(f, "unknown block status {status:#04x}")
+            }
+            Error::BlockIO => "block I/O error",
+            Error::NoDataBuf => "no data buffer provided",
+            Error::InvalidDataBufSize => "invalid data buffer size",
+            Error::SectorOutOfRange => "sector out of range",
+            Error::QueueFull => "request queue is full",
+            Error::RequestTimeout => "request timed out",
+            Error::ReadOnlyDevice => "device is read-only",
+            Error::DeviceNeedsReset => "device needs reset",
+            Error::WriteVerifyMismatch => "data read back does not match write",
+            Error::ShortTransfer => "device transferred less data than requested",
+            Error::SelfTestFailed => "device self test failed",
+            Error::RequestTooLarge => "request exceeds the device segment limits",
+            Error::InvalidQueue => "no such request queue",
+            Error::BlockNotSupported => "operation not supported by device",
+        })
+    }
+}
+
+/// Result of operations on block devices
+pub type BlockResult<T> = Result<T, Error>;
+
 #[repr(C)]
+#[derive(Default)]
 /// Header used for virtio block requests
 struct BlockRequestHeader {
     request: u32,
@@ -94,14 +372,31 @@ struct BlockRequestHeader {
 }
 
 #[repr(C)]
+#[derive(Default, Clone, Copy)]
+/// Range used as the data of discard and write zeroes requests
+struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
 /// Footer used for virtio block requests
 struct BlockRequestFooter {
     status: u8,
 }
 
+// Layouts the device expects for the request header, segment and footer
+const _: () = assert!(core::mem::size_of::<BlockRequestHeader>() == 16);
+const _: () = assert!(core::mem::size_of::<DiscardWriteZeroesSegment>() == 16);
+const _: () = assert!(core::mem::size_of::<BlockRequestFooter>() == 1);
+
 const SECTOR_SIZE: usize = 512;
 
-#[repr(C)]
+// Aligned so that buffers handed to devices never straddle a cache line
+// more than they have to
+#[repr(C, align(64))]
 pub struct SectorBuf([u8; SECTOR_SIZE]);
 
 impl SectorBuf {
@@ -123,228 +418,3111 @@ impl SectorBuf {
     }
 }
 
+impl Deref for SectorBuf {
+    type Target = [u8; SECTOR_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SectorBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Serial number of a device, without the NUL or space padding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceId {
+    id: [u8; 20],
+    len: usize,
+}
+
+impl DeviceId {
+    fn new(id: [u8; 20]) -> DeviceId {
+        let len = id
+            .iter()
+            .rposition(|&b| b != 0 && b != b' ')
+            .map_or(0, |last| last + 1);
+        DeviceId { id, len }
+    }
+}
+
+impl Deref for DeviceId {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.id[..self.len]
+    }
+}
+
 pub trait SectorRead {
     /// Read a single sector (512 bytes) from the block device. `data` must be
     /// exactly 512 bytes long.
-    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error>;
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()>;
+
+    /// Read a single sector into a buffer that is suitably sized and aligned
+    /// for any device by construction
+    fn read_sector(&self, sector: u64, buf: &mut SectorBuf) -> BlockResult<()> {
+        self.read(sector, buf.as_mut_bytes())
+    }
+
+    /// Read consecutive sectors starting at `sector` straight into `region`,
+    /// the length of which must be a multiple of 512 bytes.
+    fn read_to_region(&self, sector: u64, region: &mut MemoryRegion) -> BlockResult<()> {
+        if region.length() % SectorBuf::len() as u64 != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        for (i, data) in region
+            .as_bytes()
+            .chunks_exact_mut(SectorBuf::len())
+            .enumerate()
+        {
+            self.read(sector + i as u64, data)?;
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of the blocks the device transfers. Sector numbers and
+    /// buffers are always in 512-byte units but accesses to devices with
+    /// larger blocks are best made in whole, aligned, blocks.
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// Iterate over `count` sectors starting at `start`, reading each one as
+    /// it is requested. Iteration stops after the first error.
+    fn sectors(&self, start: u64, count: u64) -> SectorIter<'_, Self>
+    where
+        Self: Sized,
+    {
+        SectorIter::new(self, start, count)
+    }
+}
+
+/// Iterator over a run of sectors returned by [`SectorRead::sectors`]. Use
+/// [`SectorIter::new`] directly for `dyn SectorRead` devices.
+pub struct SectorIter<'a, T: SectorRead + ?Sized> {
+    device: &'a T,
+    next: u64,
+    end: u64,
+}
+
+#[allow(dead_code)]
+impl<'a, T: SectorRead + ?Sized> SectorIter<'a, T> {
+    pub fn new(device: &'a T, start: u64, count: u64) -> Self {
+        SectorIter {
+            device,
+            next: start,
+            end: start.saturating_add(count),
+        }
+    }
+}
+
+impl<'a, T: SectorRead + ?Sized> Iterator for SectorIter<'a, T> {
+    type Item = BlockResult<SectorBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+
+        let mut data = SectorBuf::new();
+        let result = self.device.read(self.next, data.as_mut_bytes());
+        self.next = if result.is_ok() {
+            self.next + 1
+        } else {
+            self.end
+        };
+        Some(result.map(|_| data))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = usize::try_from(self.end - self.next).unwrap_or(usize::MAX);
+        (remaining, Some(remaining))
+    }
 }
 
+#[cfg(feature = "block-write")]
 pub trait SectorWrite {
-    /// Write a single sector (512 bytes) from the block device. `data` must be
+    /// Write a single sector (512 bytes) to the block device. `data` must be
     /// exactly 512 bytes long.
-    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error>;
-    fn flush(&self) -> Result<(), Error>;
+    fn write(&self, sector: u64, data: &[u8]) -> BlockResult<()>;
+    fn flush(&self) -> BlockResult<()>;
+
+    /// Write consecutive sectors starting at `sector` straight from
+    /// `region`, the length of which must be a multiple of 512 bytes.
+    fn write_from_region(&self, sector: u64, region: &MemoryRegion) -> BlockResult<()> {
+        if region.length() % SectorBuf::len() as u64 != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        for (i, data) in region
+            .as_slice::<u8>(0, region.length())
+            .chunks_exact(SectorBuf::len())
+            .enumerate()
+        {
+            self.write(sector + i as u64, data)?;
+        }
+        Ok(())
+    }
+
+    /// Write a single sector and flush it so it is durable before any later
+    /// write. On devices without a volatile write cache the flush is a no-op
+    /// and this is a plain write.
+    fn write_ordered(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        self.write(sector, data)?;
+        self.flush()
+    }
+}
+
+#[cfg(feature = "block-write")]
+/// Devices that can be both read from and written to. Implemented for every
+/// type that implements both traits so it can be used as `dyn SectorReadWrite`.
+pub trait SectorReadWrite: SectorRead + SectorWrite {
+    /// Write a single sector and read it back to check that the data landed.
+    /// This doubles the I/O so is only worth it for critical data.
+    #[allow(dead_code)]
+    fn write_verify(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        self.write(sector, data)?;
+
+        let mut readback = SectorBuf::new();
+        self.read(sector, readback.as_mut_bytes())?;
+        if readback.as_bytes() != data {
+            return Err(Error::WriteVerifyMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl<T: SectorRead + SectorWrite + ?Sized> SectorReadWrite for T {}
+
+/// Run `probe` against each of `devices` in order, e.g. to look for a usable
+/// ESP, returning the result for the first device that it succeeds on.
+#[allow(dead_code)]
+pub fn try_boot<T, R, E, F>(devices: &mut [T], mut probe: F) -> Option<R>
+where
+    T: SectorRead,
+    F: FnMut(&mut T) -> Result<R, E>,
+{
+    devices.iter_mut().find_map(|d| probe(d).ok())
+}
+
+/// Load `byte_len` bytes starting at `start_sector` to the physical address
+/// `dest`. Whole sectors are read straight into place, a trailing partial
+/// sector is read into a scratch buffer so nothing past the end is written.
+#[allow(dead_code)]
+pub fn load_range<T: SectorRead + ?Sized>(
+    device: &T,
+    start_sector: u64,
+    byte_len: usize,
+    dest: u64,
+) -> BlockResult<()> {
+    let whole = (byte_len / SectorBuf::len()) as u64;
+    let tail = byte_len % SectorBuf::len();
+
+    if whole > 0 {
+        let mut region = MemoryRegion::new(dest, whole * SectorBuf::len() as u64);
+        device.read_to_region(start_sector, &mut region)?;
+    }
+
+    if tail > 0 {
+        let mut data = SectorBuf::new();
+        device.read(start_sector + whole, data.as_mut_bytes())?;
+        let offset = whole * SectorBuf::len() as u64;
+        let mut region = MemoryRegion::new(dest + offset, tail as u64);
+        region.as_bytes().copy_from_slice(&data.as_bytes()[..tail]);
+    }
+
+    Ok(())
+}
+
+/// Fill `dest` from fragmented runs of `(start_sector, count)` sectors in
+/// order, with one request per run. The runs must add up to exactly the
+/// length of `dest`, reading stops at the first error.
+#[allow(dead_code)]
+pub fn read_runs<T: SectorRead + ?Sized>(
+    device: &T,
+    runs: &[(u64, u32)],
+    dest: &mut [u8],
+) -> BlockResult<()> {
+    let total = runs
+        .iter()
+        .try_fold(0usize, |total, &(_, count)| {
+            (count as usize)
+                .checked_mul(SectorBuf::len())
+                .and_then(|len| total.checked_add(len))
+        })
+        .ok_or(Error::InvalidDataBufSize)?;
+    if total != dest.len() {
+        return Err(Error::InvalidDataBufSize);
+    }
+
+    let mut offset = 0;
+    for &(start_sector, count) in runs {
+        let len = count as usize * SectorBuf::len();
+        if len == 0 {
+            continue;
+        }
+        let mut region = MemoryRegion::from_bytes(&mut dest[offset..offset + len]);
+        device.read_to_region(start_sector, &mut region)?;
+        offset += len;
+    }
+    Ok(())
 }
 
-#[derive(PartialEq, Eq, Copy, Clone)]
-enum RequestType {
-    Read = 0,
-    Write = 1,
+/// Request types of virtio block requests, the value goes in the request
+/// header. Writes, discards and write zeroes are only sent by the write path.
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(not(feature = "block-write"), allow(dead_code))]
+pub enum BlockOp {
+    In = 0,
+    Out = 1,
     Flush = 4,
+    GetId = 8,
+    Discard = 11,
+    WriteZeroes = 13,
+}
+
+impl BlockOp {
+    // Whether the device writes into the data buffers of the request
+    fn data_is_write(self) -> bool {
+        matches!(self, BlockOp::In | BlockOp::GetId)
+    }
+}
+
+// Source of the data descriptors of a request
+enum RequestData<'b> {
+    None,
+    // Caller owned buffers given as address and length
+    Buffers(&'b [(u64, u32)]),
+    // Copied into the request's own storage
+    #[cfg_attr(not(feature = "block-write"), allow(dead_code))]
+    Segment(DiscardWriteZeroesSegment),
 }
 
+// Booting only needs to read, the rest of the driver's API is marked
+// allow(dead_code) until something besides the tests makes use of it
 impl<'a> VirtioBlockDevice<'a> {
     pub fn new(transport: &'a mut dyn VirtioTransport) -> VirtioBlockDevice<'a> {
         VirtioBlockDevice {
             transport,
-            state: RefCell::new(DriverState::default()),
-            read_only: false,
+            queues: Default::default(),
+            num_queues: 1,
+            features: 0,
+            capacity: 0,
+            block_size: SECTOR_SIZE as u32,
+            max_segments: 0,
+            max_segment_size: u32::MAX,
+            timeout: DEFAULT_TIMEOUT,
+            read_retries: 0,
+            max_queue: QUEUE_SIZE,
+            required_features: 0,
+            scratch: RefCell::new(SectorBuf::new()),
+            stopped: Cell::new(false),
         }
     }
 
-    pub fn init(&mut self) -> Result<(), VirtioError> {
-        const VIRTIO_SUBSYSTEM_BLOCK: u32 = 0x2;
-        const VIRTIO_F_VERSION_1: u64 = 1 << 32;
-        const VIRTIO_BLK_F_RO: u64 = 1 << 5;
-
-        const VIRTIO_STATUS_RESET: u32 = 0;
-        const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
-        const VIRTIO_STATUS_DRIVER: u32 = 2;
-        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
-        const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
-        const VIRTIO_STATUS_FAILED: u32 = 128;
+    /// Configure the device before initialising it, `new()` followed by
+    /// `init()` uses the defaults
+    #[allow(dead_code)]
+    pub fn builder(transport: &'a mut dyn VirtioTransport) -> VirtioBlockDeviceBuilder<'a> {
+        VirtioBlockDeviceBuilder {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            max_queue: QUEUE_SIZE,
+            required_features: 0,
+        }
+    }
 
-        // Initialise the transport
-        self.transport.init(VIRTIO_SUBSYSTEM_BLOCK)?;
+    /// Set the number of times the device is polled for the completion of a
+    /// request before giving up with `Error::RequestTimeout`. The device is
+    /// reset when a request times out and `init()` must be called again
+    /// before further use.
+    #[allow(dead_code)]
+    pub fn set_timeout(&mut self, iterations: u64) {
+        self.timeout = iterations;
+    }
 
-        // Reset device
-        self.transport.set_status(VIRTIO_STATUS_RESET);
+    /// Retry a read up to `retries` times when the device reports an I/O
+    /// error, for backends that fail transiently while stalled. Off by
+    /// default so genuine errors are not delayed.
+    #[allow(dead_code)]
+    pub fn set_read_retries(&mut self, retries: u32) {
+        self.read_retries = retries;
+    }
 
-        // Acknowledge
-        self.transport.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+    /// Negotiate with the device and set up the queues. This may be called
+    /// again, e.g. after an earlier attempt failed or to recover the device,
+    /// as the device is always reset and the rings set up from scratch.
+    pub fn init(&mut self) -> Result<(), VirtioError> {
+        const VIRTIO_SUBSYSTEM_BLOCK: u32 = 0x2;
 
-        // And advertise driver
-        self.transport.add_status(VIRTIO_STATUS_DRIVER);
+        // Ring indices left from an earlier init() must not be carried over
+        // to the reset device
+        self.clear_state();
 
-        // Request device features
-        let device_features = self.transport.get_features();
+        // Features understood by this driver
+        let supported_features = VIRTIO_F_VERSION_1
+            | VIRTIO_F_RING_INDIRECT_DESC
+            | VIRTIO_BLK_F_SIZE_MAX
+            | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_GEOMETRY
+            | VIRTIO_BLK_F_RO
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_BLK_F_TOPOLOGY
+            | VIRTIO_BLK_F_CONFIG_WCE
+            | VIRTIO_BLK_F_MQ
+            | VIRTIO_BLK_F_DISCARD
+            | VIRTIO_BLK_F_WRITE_ZEROES;
 
-        if device_features & VIRTIO_F_VERSION_1 != VIRTIO_F_VERSION_1 {
+        self.features = self
+            .transport
+            .init_common(VIRTIO_SUBSYSTEM_BLOCK, supported_features)?;
+        if !self.has_features(self.required_features) {
             self.transport.add_status(VIRTIO_STATUS_FAILED);
-            return Err(VirtioError::LegacyOnly);
+            return Err(VirtioError::FeatureNegotiationFailed);
         }
 
-        // Detect if device is read-only
-        self.read_only = (device_features & VIRTIO_BLK_F_RO) == VIRTIO_BLK_F_RO;
+        // Multiqueue devices may offer more queues than are used
+        self.num_queues = 1;
+        if self.has_features(VIRTIO_BLK_F_MQ) {
+            // u16 num_queues at 0x22
+            let num_queues = (self.transport.read_device_config(0x20) >> 16) as usize;
+            self.num_queues = num_queues.clamp(1, MAX_QUEUES);
+        }
 
-        // Don't support any advanced features for now
-        let supported_features = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO;
+        // Each request needs at least three descriptors
+        let mut min_queue_size = QUEUE_SIZE;
+        for (i, state) in self.queues[..self.num_queues].iter().enumerate() {
+            let mut state = state.borrow_mut();
+            let queue_size = state
+                .queue
+                .setup(&*self.transport, i as u16, self.max_queue, 3)?;
+            state.indirect_in_use = false;
+            min_queue_size = core::cmp::min(min_queue_size, queue_size);
+        }
 
-        // Report driver features
-        self.transport
-            .set_features(device_features & supported_features);
+        // Report driver ready
+        self.transport.driver_ok();
 
-        self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
-        if self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK != VIRTIO_STATUS_FEATURES_OK {
-            self.transport.add_status(VIRTIO_STATUS_FAILED);
-            return Err(VirtioError::FeatureNegotiationFailed);
+        // The logical block size is only reported if the feature is offered,
+        // ignore values that are not a power-of-two multiple of the sector size
+        self.block_size = SECTOR_SIZE as u32;
+        if self.has_features(VIRTIO_BLK_F_BLK_SIZE) {
+            let block_size = self.transport.read_device_config(0x14);
+            if block_size.is_power_of_two() && block_size >= SECTOR_SIZE as u32 {
+                self.block_size = block_size;
+            }
         }
 
-        // Program queues
-        self.transport.set_queue(0);
+        // Requests are limited by the descriptors left after the header and
+        // footer and by any limits the device reports. Zero means no limit.
+        let chain_size = if self.has_features(VIRTIO_F_RING_INDIRECT_DESC) {
+            INDIRECT_SIZE
+        } else {
+            min_queue_size
+        };
+        self.max_segments = (chain_size - 2) as u32;
+        if self.has_features(VIRTIO_BLK_F_SEG_MAX) {
+            match self.transport.read_device_config(0x0c) {
+                0 => {}
+                seg_max => self.max_segments = core::cmp::min(self.max_segments, seg_max),
+            }
+        }
+        self.max_segment_size = u32::MAX;
+        if self.has_features(VIRTIO_BLK_F_SIZE_MAX) {
+            match self.transport.read_device_config(0x08) {
+                0 => {}
+                size_max => self.max_segment_size = size_max,
+            }
+        }
 
-        let max_queue = self.transport.get_queue_max_size();
+        // Cache the capacity so that requests can be cheaply bounds checked
+        self.capacity = match self.transport.read_device_config_u64(0) {
+            Ok(capacity) => capacity,
+            Err(e) => {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(e);
+            }
+        };
 
-        // Hardcoded queue size to QUEUE_SIZE at the moment
-        if max_queue < QUEUE_SIZE as u16 {
-            self.transport.add_status(VIRTIO_STATUS_FAILED);
-            return Err(VirtioError::QueueTooSmall);
-        }
-        self.transport.set_queue_size(QUEUE_SIZE as u16);
+        Ok(())
+    }
 
-        // Update all queue parts
-        let state = self.state.borrow_mut();
-        let addr = state.descriptors.as_ptr() as u64;
-        self.transport.set_descriptors_address(addr);
+    /// Reset the device and forget about all queue state, in flight requests
+    /// are abandoned. `init()` must be called again before further use.
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.transport.reset();
+        self.clear_state();
+    }
 
-        let addr = (&state.avail as *const _) as u64;
-        self.transport.set_avail_ring(addr);
+    // Forget everything learnt from and set up with the device
+    fn clear_state(&mut self) {
+        for state in &mut self.queues {
+            *state.get_mut() = DriverState::default();
+        }
+        self.num_queues = 1;
+        self.features = 0;
+        self.capacity = 0;
+        self.block_size = SECTOR_SIZE as u32;
+        self.max_segments = 0;
+        self.max_segment_size = u32::MAX;
+        self.stopped.set(false);
+    }
 
-        let addr = (&state.used as *const _) as u64;
-        self.transport.set_used_ring(addr);
+    /// Whether the device only supports reading
+    pub fn is_read_only(&self) -> bool {
+        self.has_features(VIRTIO_BLK_F_RO)
+    }
 
-        // Confirm queue
-        self.transport.set_queue_enable();
+    /// Operations the device accepts. Reads and serial number requests are
+    /// always available, the rest depend on the negotiated features and on
+    /// the write path being built in.
+    #[allow(dead_code)]
+    pub fn supported_ops(&self) -> SupportedOps {
+        let mut ops = SupportedOps::READ | SupportedOps::GET_ID;
+        if cfg!(feature = "block-write") && !self.is_read_only() {
+            ops |= SupportedOps::WRITE;
+            for (feature, op) in [
+                (VIRTIO_BLK_F_FLUSH, SupportedOps::FLUSH),
+                (VIRTIO_BLK_F_DISCARD, SupportedOps::DISCARD),
+                (VIRTIO_BLK_F_WRITE_ZEROES, SupportedOps::WRITE_ZEROES),
+            ] {
+                ops.set(op, self.has_features(feature));
+            }
+        }
+        ops
+    }
 
-        // Report driver ready
-        self.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+    /// Feature bits negotiated with the device
+    #[allow(dead_code)]
+    pub fn features(&self) -> u64 {
+        self.features
+    }
 
-        Ok(())
+    /// Whether all of the given feature bits were negotiated
+    pub fn has_features(&self, features: u64) -> bool {
+        self.features & features == features
     }
 
-    // Number of sectors that this device holds
-    pub fn get_capacity(&self) -> u64 {
-        u64::from(self.transport.read_device_config(0))
-            | u64::from(self.transport.read_device_config(4)) << 32
+    /// Logical block size of the device in bytes. Requests are still made in
+    /// 512-byte sectors, covering whole, aligned, blocks only avoids the
+    /// device having to read and modify partial blocks.
+    #[allow(dead_code)]
+    pub fn block_size(&self) -> u32 {
+        self.block_size
     }
 
-    fn request(
-        &self,
-        sector: u64,
-        data: Option<&mut [u8]>,
-        request: RequestType,
-    ) -> Result<(), Error> {
-        const VIRTQ_DESC_F_NEXT: u16 = 1;
-        const VIRTQ_DESC_F_WRITE: u16 = 2;
+    /// Maximum number of data buffers in a single vectored request
+    #[allow(dead_code)]
+    pub fn max_segments(&self) -> u32 {
+        self.max_segments
+    }
 
-        const VIRTIO_BLK_S_OK: u8 = 0;
-        const VIRTIO_BLK_S_IOERR: u8 = 1;
-        const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+    /// Maximum size in bytes of each data buffer in a vectored request
+    #[allow(dead_code)]
+    pub fn max_segment_size(&self) -> u32 {
+        self.max_segment_size
+    }
 
-        let header = BlockRequestHeader {
-            request: request as u32,
-            reserved: 0,
-            sector,
-        };
+    /// Write caching mode of the device. Without VIRTIO_BLK_F_CONFIG_WCE the
+    /// mode cannot be queried so writeback is assumed and writes should be
+    /// flushed.
+    #[allow(dead_code)]
+    pub fn cache_mode(&self) -> CacheMode {
+        if !self.has_features(VIRTIO_BLK_F_CONFIG_WCE) {
+            return CacheMode::WriteBack;
+        }
+        // u8 writeback at 0x20
+        match self.transport.read_device_config(0x20) as u8 {
+            0 => CacheMode::WriteThrough,
+            _ => CacheMode::WriteBack,
+        }
+    }
 
-        let footer = BlockRequestFooter { status: 0 };
+    /// Switch the write caching mode, only possible with
+    /// VIRTIO_BLK_F_CONFIG_WCE
+    #[allow(dead_code)]
+    pub fn set_cache_mode(&mut self, mode: CacheMode) -> BlockResult<()> {
+        if !self.has_features(VIRTIO_BLK_F_CONFIG_WCE) {
+            return Err(Error::BlockNotSupported);
+        }
+        self.transport.write_device_config_u8(0x20, mode as u8);
+        Ok(())
+    }
 
-        let mut state = self.state.borrow_mut();
+    /// Legacy CHS geometry, only reported by some devices
+    #[allow(dead_code)]
+    pub fn geometry(&self) -> Option<Geometry> {
+        if !self.has_features(VIRTIO_BLK_F_GEOMETRY) {
+            return None;
+        }
+        // struct virtio_blk_geometry { le16 cylinders; u8 heads; u8 sectors; }
+        let geometry = self.transport.read_device_config(0x10);
+        Some(Geometry {
+            cylinders: geometry as u16,
+            heads: (geometry >> 16) as u8,
+            sectors: (geometry >> 24) as u8,
+        })
+    }
 
-        let next_head = state.next_head;
-        let mut d = &mut state.descriptors[next_head];
-        let next_desc = (next_head + 1) % QUEUE_SIZE;
-        d.addr = (&header as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestHeader>() as u32;
-        d.flags = VIRTQ_DESC_F_NEXT;
-        d.next = next_desc as u16;
+    /// Preferred alignment and size of requests, only reported by some devices
+    #[allow(dead_code)]
+    pub fn topology(&self) -> Option<Topology> {
+        if !self.has_features(VIRTIO_BLK_F_TOPOLOGY) {
+            return None;
+        }
+        // u8 physical_block_exp; u8 alignment_offset; le16 min_io_size;
+        // le32 opt_io_size
+        let topology = self.transport.read_device_config(0x18);
+        Some(Topology {
+            physical_block_exp: topology as u8,
+            alignment_offset: (topology >> 8) as u8,
+            min_io_size: (topology >> 16) as u16,
+            opt_io_size: self.transport.read_device_config(0x1c),
+        })
+    }
 
-        let mut d = &mut state.descriptors[next_desc];
-        let next_desc = (next_desc + 1) % QUEUE_SIZE;
-        if request != RequestType::Flush {
-            match data {
-                None => {
-                    return Err(Error::NoDataBuf);
-                }
-                Some(data) => {
-                    if data.len() != SectorBuf::len() {
-                        return Err(Error::InvalidDataBufSize);
-                    }
-                    d.addr = data.as_ptr() as u64;
-                    d.length = SectorBuf::len() as u32;
-                }
-            }
+    /// Limits the device places on discard requests, if it supports them.
+    /// Devices that leave a limit at zero are taken not to have one.
+    #[allow(dead_code)]
+    pub fn discard_limits(&self) -> Option<DiscardLimits> {
+        if !self.has_features(VIRTIO_BLK_F_DISCARD) {
+            return None;
         }
+        let limit = |offset| match self.transport.read_device_config(offset) {
+            0 => u32::MAX,
+            limit => limit,
+        };
+        // le32 max_discard_sectors; le32 max_discard_seg;
+        // le32 discard_sector_alignment
+        Some(DiscardLimits {
+            max_sectors: limit(0x24),
+            max_segments: limit(0x28),
+            sector_alignment: self.transport.read_device_config(0x2c),
+        })
+    }
 
-        d.flags = VIRTQ_DESC_F_NEXT
-            | if request == RequestType::Read {
-                VIRTQ_DESC_F_WRITE
-            } else {
-                0
-            };
-        d.next = next_desc as u16;
+    /// Number of sectors that this device holds. Virtio always reports the
+    /// capacity in 512-byte sectors. The value is read at `init()`, retrying
+    /// if the config generation changes so it is never torn.
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
 
-        let mut d = &mut state.descriptors[next_desc];
-        d.addr = (&footer as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestFooter>() as u32;
-        d.flags = VIRTQ_DESC_F_WRITE;
-        d.next = 0;
+    /// Totals of the requests submitted to and completed by the device since
+    /// it was initialised
+    #[allow(dead_code)]
+    pub fn stats(&self) -> BlockStats {
+        let mut stats = BlockStats::default();
+        for state in &self.queues[..self.num_queues] {
+            let queue_stats = state.borrow().stats;
+            stats.submitted += queue_stats.submitted;
+            stats.completed += queue_stats.completed;
+            stats.bytes += queue_stats.bytes;
+        }
+        stats
+    }
 
-        // Update ring to point to head of chain. Fence. Then update idx
-        let avail_index = state.avail.idx;
-        state.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = state.next_head as u16;
-        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+    /// Number of descriptors in the first queue not held by in-flight
+    /// requests. A read or write of a single buffer needs three, submitting
+    /// with fewer free fails with `Error::QueueFull`.
+    #[allow(dead_code)]
+    pub fn free_descriptors(&self) -> usize {
+        self.queues[0].borrow().queue.num_free()
+    }
 
-        state.avail.idx = state.avail.idx.wrapping_add(1);
+    /// Number of request queues set up, requests go to the first one unless
+    /// another is chosen. More than one is only used with VIRTIO_BLK_F_MQ.
+    #[allow(dead_code)]
+    pub fn num_queues(&self) -> usize {
+        self.num_queues
+    }
 
-        // Next free descriptor to use
-        state.next_head = (next_desc + 1) % QUEUE_SIZE;
+    /// Read consecutive sectors starting at `sector` using a single request.
+    /// The length of `data` must be a non-zero multiple of the block size, a
+    /// buffer larger than `max_segment_size()` fails with
+    /// `Error::RequestTooLarge`.
+    pub fn read_multi(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        self.read_multi_on(0, sector, data)
+    }
 
-        // Notify queue has been updated
-        self.transport.notify_queue(0);
+    /// Read consecutive sectors like `read_multi()`, but if the device limits
+    /// or cuts short the single request fall back to reading in chunks of
+    /// at most `max_segment_size()` in whole sectors. When that would not
+    /// split the request it is read a sector at a time instead.
+    #[allow(dead_code)]
+    pub fn read_large(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        let error = match self.read_multi(sector, data) {
+            Err(e @ (Error::RequestTooLarge | Error::ShortTransfer)) => e,
+            result => return result,
+        };
 
-        // Check for the completion of the request
-        while unsafe { core::ptr::read_volatile(&state.used.idx) } != state.avail.idx {
-            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        let sector_size = SectorBuf::len();
+        let mut chunk = core::cmp::max(
+            self.max_segment_size as usize / sector_size * sector_size,
+            sector_size,
+        );
+        if chunk >= data.len() {
+            chunk = sector_size;
         }
-
-        match footer.status {
-            VIRTIO_BLK_S_OK => Ok(()),
-            VIRTIO_BLK_S_IOERR => Err(Error::BlockIO),
-            VIRTIO_BLK_S_UNSUPP => Err(Error::BlockNotSupported),
-            _ => Err(Error::BlockNotSupported),
+        // A single sector cannot be split any further
+        if chunk >= data.len() {
+            return Err(error);
+        }
+        let sectors_per_chunk = (chunk / SectorBuf::len()) as u64;
+        for (i, data) in data.chunks_mut(chunk).enumerate() {
+            self.read_multi(sector + i as u64 * sectors_per_chunk, data)?;
         }
+        Ok(())
     }
-}
 
-impl<'a> SectorRead for VirtioBlockDevice<'a> {
-    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
-        self.request(sector, Some(data), RequestType::Read)
-    }
-}
+    /// Read consecutive sectors like `read_multi()` but treat those past the
+    /// end of the device as holes that read as zeroes, as with a sparse file.
+    /// Useful for minimally sized images whose filesystem claims more
+    /// sectors than the disk holds.
+    #[allow(dead_code)]
+    pub fn read_sparse(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.is_empty() || data.len() % SectorBuf::len() != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let available = self
+            .capacity
+            .saturating_sub(sector)
+            .saturating_mul(SectorBuf::len() as u64);
+        if data.len() as u64 <= available {
+            return self.read_multi(sector, data);
+        }
 
-impl<'a> SectorWrite for VirtioBlockDevice<'a> {
-    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
-        if self.read_only {
-            return Err(Error::BlockNotSupported);
+        let (present, past_end) = data.split_at_mut(available as usize);
+        past_end.fill(0);
+        if present.is_empty() {
+            return Ok(());
         }
-        self.request(sector, Some(data), RequestType::Write)
+        self.read_multi(sector, present)
     }
 
-    fn flush(&self) -> Result<(), Error> {
-        if self.read_only {
-            return Err(Error::BlockNotSupported);
+    /// Read consecutive sectors like `read_multi()` using request queue
+    /// `queue`, which must be less than `num_queues()`
+    pub fn read_multi_on(&self, queue: usize, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.len() > self.max_segment_size as usize {
+            return Err(Error::RequestTooLarge);
         }
-        self.request(0, None, RequestType::Flush)
+        let data = MemoryRegion::from_bytes(data);
+        self.request(queue, sector, Some(data), BlockOp::In)
+    }
+
+    /// Read a single sector into uninitialised memory, avoiding zeroing a
+    /// buffer that is about to be overwritten. On success the device has
+    /// written every byte and the buffer is returned as initialised.
+    #[allow(dead_code)]
+    pub fn read_uninit<'b>(
+        &self,
+        sector: u64,
+        data: &'b mut [MaybeUninit<u8>; SECTOR_SIZE],
+    ) -> BlockResult<&'b mut [u8; SECTOR_SIZE]> {
+        let region = MemoryRegion::new(data.as_mut_ptr() as u64, SECTOR_SIZE as u64);
+        self.request(0, sector, Some(region), BlockOp::In)?;
+        // SAFETY: A read that completes without a short transfer has filled
+        // the whole buffer
+        Ok(unsafe { &mut *(data as *mut _ as *mut [u8; SECTOR_SIZE]) })
+    }
+
+    /// Submit a read of consecutive sectors without waiting for it to
+    /// complete. The returned token must be passed to `wait()` (or polled until
+    /// complete) before `data` is used again.
+    ///
+    /// # Safety
+    ///
+    /// The device writes into `data` until the request completes but the
+    /// borrow ends as soon as the token goes away. The token must not be
+    /// dropped or forgotten until `wait()` has returned, `poll()` has returned
+    /// `Ok(true)` or an error, or it has been handed to `wait_async()`.
+    #[allow(dead_code)]
+    pub unsafe fn submit_read<'b>(
+        &self,
+        sector: u64,
+        data: &'b mut [u8],
+    ) -> BlockResult<RequestToken<'b>> {
+        // SAFETY: The caller upholds the same contract
+        unsafe { self.submit_read_on(0, sector, data) }
+    }
+
+    /// Submit a read like `submit_read()` using request queue `queue`, so
+    /// that it is processed in parallel with requests on the other queues
+    ///
+    /// # Safety
+    ///
+    /// As for `submit_read()`, the token must be kept until the request has
+    /// completed.
+    #[allow(dead_code)]
+    pub unsafe fn submit_read_on<'b>(
+        &self,
+        queue: usize,
+        sector: u64,
+        data: &'b mut [u8],
+    ) -> BlockResult<RequestToken<'b>> {
+        let data = MemoryRegion::from_bytes(data);
+        let head = self.submit(queue, sector, Some(data), BlockOp::In)?;
+        Ok(RequestToken {
+            queue,
+            head,
+            _data: PhantomData,
+        })
+    }
+
+    /// Read consecutive sectors starting at `sector` into several buffers
+    /// using a single request. The combined length of the buffers must be a
+    /// non-zero multiple of the block size. Requests with more buffers than
+    /// `max_segments()` or buffers larger than `max_segment_size()` fail with
+    /// `Error::RequestTooLarge`.
+    #[allow(dead_code)]
+    pub fn read_vectored(&self, sector: u64, bufs: &mut [&mut [u8]]) -> BlockResult<()> {
+        if bufs.is_empty() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        if bufs.len() > self.max_segments as usize
+            || bufs
+                .iter()
+                .any(|b| b.len() > self.max_segment_size as usize)
+        {
+            return Err(Error::RequestTooLarge);
+        }
+
+        let mut segments = [(0u64, 0u32); INDIRECT_SIZE - 2];
+        let mut length = 0;
+        for (segment, buf) in segments.iter_mut().zip(bufs.iter_mut()) {
+            if buf.is_empty() {
+                return Err(Error::InvalidDataBufSize);
+            }
+            let buf_length = u32::try_from(buf.len()).map_err(|_| Error::InvalidDataBufSize)?;
+            *segment = (buf.as_mut_ptr() as u64, buf_length);
+            length += u64::from(buf_length);
+        }
+        self.check_transfer(sector, length)?;
+
+        let head = self.enqueue(
+            0,
+            sector,
+            RequestData::Buffers(&segments[..bufs.len()]),
+            BlockOp::In,
+        )?;
+        self.wait_request(0, head)
+    }
+
+    #[cfg(feature = "block-write")]
+    /// Tell the device that `num_sectors` starting at `sector` are no longer
+    /// in use so the backing storage can be reclaimed. Ranges larger than the
+    /// device accepts at once are split over several requests.
+    #[allow(dead_code)]
+    pub fn discard(&self, sector: u64, num_sectors: u32) -> BlockResult<()> {
+        let limits = self.discard_limits().ok_or(Error::BlockNotSupported)?;
+        if self.is_read_only() {
+            return Err(Error::ReadOnlyDevice);
+        }
+        if num_sectors == 0 {
+            return Err(Error::SectorOutOfRange);
+        }
+        match sector.checked_add(u64::from(num_sectors)) {
+            Some(end) if end <= self.capacity => {}
+            _ => return Err(Error::SectorOutOfRange),
+        }
+
+        // Each request carries a single segment
+        let mut done = 0;
+        while done < num_sectors {
+            let count = core::cmp::min(num_sectors - done, limits.max_sectors);
+            self.request_segment(sector + u64::from(done), count, 0, BlockOp::Discard)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "block-write")]
+    /// Zero `num_sectors` starting at `sector` without transferring any data.
+    /// With `unmap` the device may also discard the sectors as long as they
+    /// subsequently read back as zeroes.
+    #[allow(dead_code)]
+    pub fn write_zeroes(&self, sector: u64, num_sectors: u32, unmap: bool) -> BlockResult<()> {
+        const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+        if !self.has_features(VIRTIO_BLK_F_WRITE_ZEROES) {
+            return Err(Error::BlockNotSupported);
+        }
+        if self.is_read_only() {
+            return Err(Error::ReadOnlyDevice);
+        }
+        let flags = if unmap {
+            VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+        } else {
+            0
+        };
+        self.request_segment(sector, num_sectors, flags, BlockOp::WriteZeroes)
+    }
+
+    /// Check that the read path works by reading sector 0 twice, expecting the
+    /// same data each time and a single used ring entry per request
+    #[allow(dead_code)]
+    pub fn self_test(&self) -> BlockResult<()> {
+        let mut first = SectorBuf::new();
+        let mut second = SectorBuf::new();
+        for data in [&mut first, &mut second] {
+            let used_idx = self.used_idx();
+            self.read_multi(0, data.as_mut_bytes())?;
+            if self.used_idx() != used_idx.wrapping_add(1) {
+                return Err(Error::SelfTestFailed);
+            }
+        }
+        if first.as_bytes() != second.as_bytes() {
+            return Err(Error::SelfTestFailed);
+        }
+        Ok(())
+    }
+
+    /// Sector sized buffer kept with the device for transient use, saving
+    /// stack space for callers that need one while driving the device. It
+    /// may only be borrowed once at a time.
+    #[allow(dead_code)]
+    pub fn scratch(&self) -> RefMut<'_, SectorBuf> {
+        self.scratch.borrow_mut()
+    }
+
+    /// Read the device serial number. Serials shorter than 20 bytes are
+    /// padded by the device, with NUL bytes or spaces, which are trimmed.
+    #[allow(dead_code)]
+    pub fn get_id(&self) -> BlockResult<DeviceId> {
+        let mut id = [0u8; 20];
+        let head = self.enqueue(
+            0,
+            0,
+            RequestData::Buffers(&[(id.as_mut_ptr() as u64, id.len() as u32)]),
+            BlockOp::GetId,
+        )?;
+        self.wait_request(0, head)?;
+        Ok(DeviceId::new(id))
+    }
+
+    /// Acknowledge a pending interrupt from the device. Completions are
+    /// acknowledged automatically while waiting for requests.
+    #[allow(dead_code)]
+    pub fn ack_interrupt(&self) {
+        self.transport.ack_interrupt();
+    }
+
+    /// Check, without touching the queues, that the device is still usable:
+    /// it responds, is running and has neither failed nor asked to be reset
+    #[allow(dead_code)]
+    pub fn is_alive(&self) -> bool {
+        if !self.transport.is_present() {
+            return false;
+        }
+        let status = self.transport.get_status();
+        status & VIRTIO_STATUS_DRIVER_OK != 0
+            && status & (VIRTIO_STATUS_FAILED | VIRTIO_STATUS_DEVICE_NEEDS_RESET) == 0
+    }
+
+    fn used_idx(&self) -> u16 {
+        self.queues[0].borrow().queue.used_idx()
+    }
+
+    /// Check whether a submitted request has completed. Once this returns
+    /// `Ok(true)` or an error the request has been retired, except for
+    /// `Error::DeviceNeedsReset` after which the device must be `reset()`.
+    pub fn poll(&self, token: &RequestToken) -> BlockResult<bool> {
+        self.poll_request(token.queue, token.head)
+    }
+
+    /// Wait, up to the timeout, for the device to return every submitted
+    /// request and reclaim their descriptors, e.g. to give up on requests
+    /// whose results are no longer wanted. Returns the number of requests
+    /// reclaimed. Their results are discarded and their tokens must not be
+    /// used again. Requests the device still holds on to are left in flight.
+    /// The free descriptor list of a queue, and with it the next head handed
+    /// out, is only reset once the device holds nothing on that queue, as
+    /// descriptors still in flight must not be reused.
+    #[allow(dead_code)]
+    pub fn drain(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for state in &mut self.queues[..self.num_queues] {
+            let state = state.get_mut();
+            for _ in 0..self.timeout {
+                if state.process_used() {
+                    self.transport.ack_interrupt();
+                }
+                if state.queue.num_outstanding() == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+
+            for head in 0..state.queue.size() {
+                if state.requests[head].complete {
+                    state.requests[head].complete = false;
+                    state.free_chain(head);
+                    reclaimed += 1;
+                }
+            }
+            // Start again from a clean list once the device holds nothing
+            if state.queue.num_free() == state.queue.size() {
+                state.reset_free_list();
+            }
+        }
+        reclaimed
+    }
+
+    /// Wait for a submitted read to complete from async code, letting other
+    /// tasks run while it is in flight
+    #[allow(dead_code)]
+    pub fn wait_async<'b>(&self, token: RequestToken<'b>) -> ReadFuture<'_, 'a, 'b> {
+        ReadFuture {
+            device: self,
+            token: Some(token),
+        }
+    }
+
+    /// Wait for a submitted request to complete
+    pub fn wait(&self, token: RequestToken) -> BlockResult<()> {
+        self.wait_request(token.queue, token.head)
+    }
+
+    fn request(
+        &self,
+        queue: usize,
+        sector: u64,
+        data: Option<MemoryRegion>,
+        request: BlockOp,
+    ) -> BlockResult<()> {
+        let head = self.submit(queue, sector, data, request)?;
+        self.wait_request(queue, head)
+    }
+
+    // State of request queue `queue` if it has been set up
+    fn queue_state(&self, queue: usize) -> BlockResult<&RefCell<DriverState>> {
+        self.queues[..self.num_queues]
+            .get(queue)
+            .ok_or(Error::InvalidQueue)
+    }
+
+    // Check that a transfer of `length` bytes starting at `sector` is a whole
+    // number of logical blocks within the device
+    fn check_transfer(&self, sector: u64, length: u64) -> BlockResult<u32> {
+        // Requests are in 512-byte sectors whatever the logical block size
+        if length == 0 || length % SectorBuf::len() as u64 != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let sectors = length / SectorBuf::len() as u64;
+        match sector.checked_add(sectors) {
+            Some(end) if end <= self.capacity => {}
+            _ => return Err(Error::SectorOutOfRange),
+        }
+        u32::try_from(length).map_err(|_| Error::InvalidDataBufSize)
+    }
+
+    // The data buffer is passed as a raw region as, depending on the request
+    // type, the device either reads from or writes into it. Returns the head
+    // descriptor of the request.
+    fn submit(
+        &self,
+        queue: usize,
+        sector: u64,
+        data: Option<MemoryRegion>,
+        request: BlockOp,
+    ) -> BlockResult<usize> {
+        if request == BlockOp::Flush {
+            return self.enqueue(queue, sector, RequestData::None, request);
+        }
+
+        let data = data.ok_or(Error::NoDataBuf)?;
+        let length = self.check_transfer(sector, data.length())?;
+        self.enqueue(
+            queue,
+            sector,
+            RequestData::Buffers(&[(data.base(), length)]),
+            request,
+        )
+    }
+
+    // Submit a request operating on a range of sectors described by a
+    // segment rather than a data buffer
+    #[cfg_attr(not(feature = "block-write"), allow(dead_code))]
+    fn request_segment(
+        &self,
+        sector: u64,
+        num_sectors: u32,
+        flags: u32,
+        request: BlockOp,
+    ) -> BlockResult<()> {
+        if num_sectors == 0 {
+            return Err(Error::SectorOutOfRange);
+        }
+        match sector.checked_add(u64::from(num_sectors)) {
+            Some(end) if end <= self.capacity => {}
+            _ => return Err(Error::SectorOutOfRange),
+        }
+
+        // The header sector is unused for these requests
+        let segment = DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        };
+        let head = self.enqueue(0, 0, RequestData::Segment(segment), request)?;
+        self.wait_request(0, head)
+    }
+
+    /// Issue a request of any type, a `BlockOp` or e.g. a vendor specific
+    /// one, and wait for it to complete. Set `data_is_write` when the device
+    /// fills `data`, as for a read, and clear it when the device takes its
+    /// contents, as for a write. Returns the status byte reported by the
+    /// device, which is not checked; only the transport can fail.
+    #[allow(dead_code)]
+    pub fn submit_request(
+        &self,
+        req_type: u32,
+        sector: u64,
+        data: Option<&mut [u8]>,
+        data_is_write: bool,
+    ) -> BlockResult<u8> {
+        let head = match data {
+            None => self.enqueue_raw(0, sector, RequestData::None, req_type, data_is_write)?,
+            Some(data) => {
+                let length = u32::try_from(data.len())
+                    .ok()
+                    .filter(|&length| length != 0)
+                    .ok_or(Error::InvalidDataBufSize)?;
+                self.enqueue_raw(
+                    0,
+                    sector,
+                    RequestData::Buffers(&[(data.as_mut_ptr() as u64, length)]),
+                    req_type,
+                    data_is_write,
+                )?
+            }
+        };
+        self.wait_status(0, head)
+    }
+
+    fn enqueue(
+        &self,
+        queue: usize,
+        sector: u64,
+        data: RequestData,
+        request: BlockOp,
+    ) -> BlockResult<usize> {
+        self.enqueue_raw(queue, sector, data, request as u32, request.data_is_write())
+    }
+
+    // Build the descriptor chain for a validated request and notify the
+    // device. Returns the head descriptor which also indexes the request.
+    fn enqueue_raw(
+        &self,
+        queue: usize,
+        sector: u64,
+        data: RequestData,
+        request: u32,
+        data_is_write: bool,
+    ) -> BlockResult<usize> {
+        let descriptors = match data {
+            RequestData::None => 2,
+            RequestData::Buffers(buffers) => buffers.len() + 2,
+            RequestData::Segment(_) => 3,
+        };
+
+        if self.stopped.get() {
+            return Err(Error::DeviceNeedsReset);
+        }
+        let mut state = self.queue_state(queue)?.borrow_mut();
+        let state = &mut *state;
+
+        // Requests that need more descriptors than are free are put in the
+        // indirect table if the device supports it
+        let num_free = state.queue.num_free();
+        let indirect = descriptors > num_free
+            && !state.indirect_in_use
+            && self.has_features(VIRTIO_F_RING_INDIRECT_DESC);
+        if descriptors > INDIRECT_SIZE || (!indirect && descriptors > num_free) || num_free == 0 {
+            return Err(Error::QueueFull);
+        }
+        // The request's storage is indexed by the head of its chain so must be
+        // filled in before the chain is built
+        let head = usize::from(state.queue.next_head());
+
+        state.stats.submitted += 1;
+        let r = &mut state.requests[head];
+        r.complete = false;
+        r.header = BlockRequestHeader {
+            request,
+            reserved: 0,
+            sector,
+        };
+        // Seed the status with a value the device never reports so a chain
+        // returned without the status written is not mistaken for success
+        r.footer = BlockRequestFooter {
+            status: VIRTIO_BLK_S_INVALID,
+        };
+        let header_addr = (&r.header as *const _) as u64;
+        let footer_addr = (&r.footer as *const _) as u64;
+        if let RequestData::Segment(segment) = data {
+            r.segment = segment;
+        }
+        r.length = match data {
+            RequestData::Buffers(buffers) => buffers.iter().map(|&(_, length)| length).sum(),
+            _ => 0,
+        };
+        // Only reads are checked as devices differ in whether the status byte
+        // is counted for other requests
+        r.expected = if request == BlockOp::In as u32 {
+            r.length
+        } else {
+            0
+        };
+        r.written = 0;
+        let segment_addr = (&r.segment as *const _) as u64;
+
+        // VERSION_1 devices must accept any split of the request over the
+        // descriptors but some backends still insist on the legacy layout, so
+        // the header, each data buffer and the footer always get their own:
+        // the header read only, the data writable only when the device
+        // returns data and the one byte footer writable.
+        let mut chain = [DescSpec::default(); INDIRECT_SIZE];
+        chain[0] = DescSpec {
+            addr: header_addr,
+            length: core::mem::size_of::<BlockRequestHeader>() as u32,
+            flags: 0,
+        };
+        let mut length = 1;
+
+        // Requests without data (flush) chain the header straight to the
+        // footer
+        let data_flags = if data_is_write { VIRTQ_DESC_F_WRITE } else { 0 };
+        match data {
+            RequestData::None => {}
+            RequestData::Buffers(buffers) => {
+                for &(addr, buffer_length) in buffers {
+                    chain[length] = DescSpec {
+                        addr,
+                        length: buffer_length,
+                        flags: data_flags,
+                    };
+                    length += 1;
+                }
+            }
+            RequestData::Segment(_) => {
+                chain[length] = DescSpec {
+                    addr: segment_addr,
+                    length: core::mem::size_of::<DiscardWriteZeroesSegment>() as u32,
+                    flags: data_flags,
+                };
+                length += 1;
+            }
+        }
+
+        chain[length] = DescSpec {
+            addr: footer_addr,
+            length: core::mem::size_of::<BlockRequestFooter>() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+        };
+        let chain = &chain[..length + 1];
+        debug_assert!(chain[0].flags == 0 && chain[length].flags == VIRTQ_DESC_F_WRITE);
+
+        let pushed = if indirect {
+            fill_indirect_table(&mut state.indirect, chain);
+            state.indirect_in_use = true;
+            state.queue.push_chain(&[DescSpec {
+                addr: state.indirect.as_ptr() as u64,
+                length: (chain.len() * core::mem::size_of::<Desc>()) as u32,
+                flags: VIRTQ_DESC_F_INDIRECT,
+            }])
+        } else {
+            state.queue.push_chain(chain)
+        };
+        let pushed = pushed.map_err(|_| Error::QueueFull)?;
+        debug_assert_eq!(usize::from(pushed), head);
+
+        // Notify queue has been updated
+        self.transport.notify_queue(queue as u16);
+
+        Ok(head)
+    }
+
+    // Returns the status reported by the device once the request completes
+    fn poll_status(&self, queue: usize, head: usize) -> BlockResult<Option<u8>> {
+        let mut state = self.queue_state(queue)?.borrow_mut();
+        // The used ring is polled but the device may still have raised an
+        // interrupt that needs acknowledging
+        if state.process_used() {
+            self.transport.ack_interrupt();
+        }
+
+        let r = &mut state.requests[head];
+        if !r.complete {
+            // A device that has given up will never complete the request, it
+            // must be reset and reinitialised before it can be used again
+            if self.stopped.get()
+                || self.transport.get_status() & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0
+            {
+                return Err(Error::DeviceNeedsReset);
+            }
+            return Ok(None);
+        }
+        r.complete = false;
+        let status = unsafe { core::ptr::read_volatile(&r.footer.status) };
+        let short = r.written < r.expected;
+        let length = r.length;
+
+        // The device is done with the chain so it can be reused
+        state.free_chain(head);
+        state.stats.completed += 1;
+        if status == VIRTIO_BLK_S_OK && !short {
+            state.stats.bytes += u64::from(length);
+        }
+
+        // Catch devices that report success without filling the buffers
+        if status == VIRTIO_BLK_S_OK && short {
+            return Err(Error::ShortTransfer);
+        }
+        Ok(Some(status))
+    }
+
+    fn poll_request(&self, queue: usize, head: usize) -> BlockResult<bool> {
+        match self.poll_status(queue, head)? {
+            None => Ok(false),
+            Some(status) => check_status(status).map(|_| true),
+        }
+    }
+
+    // On timeout the device is reset so that it stops accessing the
+    // request's buffers once the caller gets them back. Every request still
+    // in flight is abandoned and the device must be initialised again.
+    fn wait_status(&self, queue: usize, head: usize) -> BlockResult<u8> {
+        // Check for the completion of the request
+        for _ in 0..self.timeout {
+            if let Some(status) = self.poll_status(queue, head)? {
+                return Ok(status);
+            }
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+            core::hint::spin_loop();
+        }
+        self.transport.reset();
+        self.stopped.set(true);
+        Err(Error::RequestTimeout)
+    }
+
+    fn wait_request(&self, queue: usize, head: usize) -> BlockResult<()> {
+        check_status(self.wait_status(queue, head)?)
+    }
+}
+
+// Map the status of a completed request to the error it reports
+fn check_status(status: u8) -> BlockResult<()> {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_IOERR => Err(Error::BlockIO),
+        VIRTIO_BLK_S_UNSUPP => Err(Error::BlockNotSupported),
+        _ => Err(Error::UnknownBlockStatus(status)),
+    }
+}
+
+impl<'a> SectorRead for VirtioBlockDevice<'a> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let mut retries = self.read_retries;
+        loop {
+            match self.read_multi(sector, data) {
+                Err(Error::BlockIO) if retries > 0 => {
+                    retries -= 1;
+                    for _ in 0..READ_RETRY_DELAY {
+                        core::hint::spin_loop();
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // The device can DMA directly into the region so use a single request
+    fn read_to_region(&self, sector: u64, region: &mut MemoryRegion) -> BlockResult<()> {
+        let data = MemoryRegion::new(region.base(), region.length());
+        self.request(0, sector, Some(data), BlockOp::In)
+    }
+
+    fn sector_size(&self) -> usize {
+        self.block_size as usize
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl<'a> SectorWrite for VirtioBlockDevice<'a> {
+    fn write(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnlyDevice);
+        }
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let data = MemoryRegion::new(data.as_ptr() as u64, data.len() as u64);
+        self.request(0, sector, Some(data), BlockOp::Out)
+    }
+
+    // The device can DMA directly out of the region so use a single request
+    fn write_from_region(&self, sector: u64, region: &MemoryRegion) -> BlockResult<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnlyDevice);
+        }
+        let data = MemoryRegion::new(region.base(), region.length());
+        self.request(0, sector, Some(data), BlockOp::Out)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnlyDevice);
+        }
+        // Without VIRTIO_BLK_F_FLUSH the device has no volatile write cache
+        // so there is nothing to flush.
+        if !self.has_features(VIRTIO_BLK_F_FLUSH) {
+            return Ok(());
+        }
+        self.request(0, 0, None, BlockOp::Flush)
+    }
+}
+
+struct CacheEntry {
+    sector: u64,
+    data: SectorBuf,
+    last_used: u64,
+    valid: bool,
+}
+
+/// Block device wrapper keeping the `N` most recently read sectors in memory
+pub struct CachedBlockDevice<T: SectorRead, const N: usize = 8> {
+    device: T,
+    entries: RefCell<[CacheEntry; N]>,
+    tick: Cell<u64>,
+}
+
+#[allow(dead_code)]
+impl<T: SectorRead, const N: usize> CachedBlockDevice<T, N> {
+    pub fn new(device: T) -> Self {
+        CachedBlockDevice {
+            device,
+            entries: RefCell::new(core::array::from_fn(|_| CacheEntry {
+                sector: 0,
+                data: SectorBuf::new(),
+                last_used: 0,
+                valid: false,
+            })),
+            tick: Cell::new(0),
+        }
+    }
+
+    /// Drop all cached sectors
+    pub fn invalidate(&self) {
+        for e in self.entries.borrow_mut().iter_mut() {
+            e.valid = false;
+        }
+    }
+
+    fn invalidate_sector(&self, sector: u64) {
+        for e in self.entries.borrow_mut().iter_mut() {
+            if e.valid && e.sector == sector {
+                e.valid = false;
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+}
+
+impl<T: SectorRead, const N: usize> SectorRead for CachedBlockDevice<T, N> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+
+        let mut entries = self.entries.borrow_mut();
+        if let Some(e) = entries.iter_mut().find(|e| e.valid && e.sector == sector) {
+            e.last_used = tick;
+            data.copy_from_slice(e.data.as_bytes());
+            return Ok(());
+        }
+
+        self.device.read(sector, data)?;
+
+        // Replace an unused entry if there is one, otherwise the least
+        // recently used
+        if let Some(e) = entries.iter_mut().min_by_key(|e| (e.valid, e.last_used)) {
+            e.sector = sector;
+            e.data.as_mut_bytes().copy_from_slice(data);
+            e.last_used = tick;
+            e.valid = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl<T: SectorRead + SectorWrite, const N: usize> SectorWrite for CachedBlockDevice<T, N> {
+    fn write(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        self.invalidate_sector(sector);
+        self.device.write(sector, data)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        self.device.flush()
+    }
+}
+
+/// Block device wrapper that turns sequential single sector reads into
+/// larger requests. Once two consecutive sectors have been read the following
+/// `N` sectors are fetched with one request and served from memory.
+pub struct CoalescingReader<T: SectorRead, const N: usize = 8> {
+    device: T,
+    buffer: RefCell<[SectorBuf; N]>,
+    // First sector held in the buffer and how many are valid
+    start: Cell<u64>,
+    count: Cell<usize>,
+    last_read: Cell<Option<u64>>,
+    // Reading ahead failed during the current run of sequential reads
+    ahead_failed: Cell<bool>,
+}
+
+#[allow(dead_code)]
+impl<T: SectorRead, const N: usize> CoalescingReader<T, N> {
+    pub fn new(device: T) -> Self {
+        CoalescingReader {
+            device,
+            buffer: RefCell::new(core::array::from_fn(|_| SectorBuf::new())),
+            start: Cell::new(0),
+            count: Cell::new(0),
+            last_read: Cell::new(None),
+            ahead_failed: Cell::new(false),
+        }
+    }
+
+    /// Drop any sectors read ahead so the next read goes to the device
+    pub fn flush(&self) {
+        self.count.set(0);
+        self.last_read.set(None);
+        self.ahead_failed.set(false);
+    }
+
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    fn read_ahead(&self, sector: u64) -> BlockResult<()> {
+        let mut buffer = self.buffer.borrow_mut();
+        let mut region =
+            MemoryRegion::new(buffer.as_mut_ptr() as u64, (N * SectorBuf::len()) as u64);
+        self.count.set(0);
+        self.device.read_to_region(sector, &mut region)?;
+        self.start.set(sector);
+        self.count.set(N);
+        Ok(())
+    }
+}
+
+impl<T: SectorRead, const N: usize> SectorRead for CoalescingReader<T, N> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+
+        let sequential = self.last_read.get().and_then(|s| s.checked_add(1)) == Some(sector);
+        self.last_read.set(Some(sector));
+        if !sequential {
+            self.ahead_failed.set(false);
+        }
+
+        let buffered = |c: &Self| {
+            let offset = sector.wrapping_sub(c.start.get());
+            (offset < c.count.get() as u64).then_some(offset as usize)
+        };
+
+        // Near the end of the device the read ahead may fail, in which case
+        // fall back to reading single sectors until the next run starts
+        if buffered(self).is_none() && sequential && N > 1 && !self.ahead_failed.get() {
+            self.ahead_failed.set(self.read_ahead(sector).is_err());
+        }
+
+        match buffered(self) {
+            Some(i) => {
+                data.copy_from_slice(self.buffer.borrow()[i].as_bytes());
+                Ok(())
+            }
+            None => self.device.read(sector, data),
+        }
+    }
+}
+
+/// Block device wrapper exposing a single partition. Sector numbers are
+/// relative to the start of the partition and accesses beyond its end fail.
+pub struct PartitionBlockDevice<T: SectorRead> {
+    device: T,
+    start_lba: u64,
+    sector_count: u64,
+}
+
+#[allow(dead_code)]
+impl<T: SectorRead> PartitionBlockDevice<T> {
+    pub fn new(device: T, start_lba: u64, sector_count: u64) -> Self {
+        PartitionBlockDevice {
+            device,
+            start_lba,
+            sector_count,
+        }
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    // Translate an access of `count` sectors to an absolute sector number
+    fn translate(&self, sector: u64, count: u64) -> BlockResult<u64> {
+        match sector.checked_add(count) {
+            Some(end) if end <= self.sector_count => Ok(self.start_lba + sector),
+            _ => Err(Error::SectorOutOfRange),
+        }
+    }
+}
+
+impl<T: SectorRead> SectorRead for PartitionBlockDevice<T> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        self.device.read(self.translate(sector, 1)?, data)
+    }
+
+    fn read_to_region(&self, sector: u64, region: &mut MemoryRegion) -> BlockResult<()> {
+        let count = region.length() / SectorBuf::len() as u64;
+        self.device
+            .read_to_region(self.translate(sector, count)?, region)
+    }
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl<T: SectorRead + SectorWrite> SectorWrite for PartitionBlockDevice<T> {
+    fn write(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        self.device.write(self.translate(sector, 1)?, data)
+    }
+
+    fn write_from_region(&self, sector: u64, region: &MemoryRegion) -> BlockResult<()> {
+        let count = region.length() / SectorBuf::len() as u64;
+        self.device
+            .write_from_region(self.translate(sector, count)?, region)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        self.device.flush()
+    }
+}
+
+/// Block device backed by a disk image in memory, such as one embedded in the
+/// firmware or loaded alongside it
+pub struct RamDisk {
+    region: MemoryRegion,
+}
+
+#[allow(dead_code)]
+impl RamDisk {
+    pub fn new(region: MemoryRegion) -> RamDisk {
+        RamDisk { region }
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.region.length() / SectorBuf::len() as u64
+    }
+
+    // Byte offset of an access of `length` bytes starting at `sector`
+    fn offset(&self, sector: u64, length: u64) -> BlockResult<u64> {
+        if length % SectorBuf::len() as u64 != 0 {
+            return Err(Error::InvalidDataBufSize);
+        }
+        match sector.checked_add(length / SectorBuf::len() as u64) {
+            Some(end) if end <= self.sector_count() => Ok(sector * SectorBuf::len() as u64),
+            _ => Err(Error::SectorOutOfRange),
+        }
+    }
+}
+
+impl SectorRead for RamDisk {
+    fn read(&self, sector: u64, data: &mut [u8]) -> BlockResult<()> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let offset = self.offset(sector, data.len() as u64)?;
+        data.copy_from_slice(self.region.as_slice(offset, data.len() as u64));
+        Ok(())
+    }
+
+    fn read_to_region(&self, sector: u64, region: &mut MemoryRegion) -> BlockResult<()> {
+        let length = region.length();
+        let offset = self.offset(sector, length)?;
+        region
+            .as_bytes()
+            .copy_from_slice(self.region.as_slice(offset, length));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl SectorWrite for RamDisk {
+    fn write(&self, sector: u64, data: &[u8]) -> BlockResult<()> {
+        if data.len() != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let offset = self.offset(sector, data.len() as u64)?;
+        let mut dest = MemoryRegion::new(self.region.base() + offset, data.len() as u64);
+        dest.as_bytes().copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::{
+        BlockRequestHeader, BlockStats, CachedBlockDevice, CoalescingReader,
+        DiscardWriteZeroesSegment, Error, PartitionBlockDevice, RamDisk, SectorBuf, SectorRead,
+        VirtioBlockDevice, QUEUE_SIZE, VIRTIO_F_VERSION_1,
+    };
+    #[cfg(feature = "block-write")]
+    use super::{SectorReadWrite, SectorWrite};
+    use crate::{
+        mem::MemoryRegion,
+        testing::MemBlockDevice,
+        virtio::{
+            AvailRing, Desc, Error as VirtioError, UsedRing, VirtioTransport,
+            VIRTIO_STATUS_DEVICE_NEEDS_RESET, VIRTIO_STATUS_FAILED, VIRTIO_STATUS_FEATURES_OK,
+            VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+        },
+    };
+
+    // Rings of one of the fake device's queues
+    #[derive(Default)]
+    struct FakeQueue {
+        size: Cell<u16>,
+        descriptors: Cell<u64>,
+        avail: Cell<u64>,
+        used: Cell<u64>,
+        last_avail_idx: Cell<u16>,
+    }
+
+    // Virtio block device backed by memory that completes requests as soon as
+    // the queue is notified
+    struct FakeTransport {
+        disk: RefCell<Vec<u8>>,
+        features: u64,
+        status: Cell<u32>,
+        queues: [FakeQueue; 2],
+        selected_queue: Cell<u16>,
+        // Queues offered with VIRTIO_BLK_F_MQ
+        num_queues: Cell<u16>,
+        short_reads: Cell<bool>,
+        // Status reported for every request in place of the real one
+        forced_status: Cell<Option<u8>>,
+        // Requests failed with an I/O error before the device recovers
+        transient_errors: Cell<u32>,
+        max_discard_sectors: Cell<u32>,
+        // Status reads that leave out FEATURES_OK before it latches
+        features_ok_delay: Cell<u32>,
+        size_max: Cell<u32>,
+        seg_max: Cell<u32>,
+        // Largest queue size that the device accepts without clamping
+        queue_size_limit: Cell<u16>,
+        queue_max_size: Cell<u16>,
+        // Config updates racing with reads of the upper half of the capacity
+        config_updates: Cell<u32>,
+        generation: Cell<u32>,
+        writeback: Cell<u8>,
+        // Leave requests pending until complete() is called
+        deferred: Cell<bool>,
+        blk_size: Cell<u32>,
+    }
+
+    impl FakeTransport {
+        fn new(sectors: usize, features: u64) -> FakeTransport {
+            FakeTransport {
+                disk: RefCell::new((0..sectors).flat_map(|s| [s as u8; 512]).collect()),
+                features: VIRTIO_F_VERSION_1 | features,
+                status: Cell::new(0),
+                queues: Default::default(),
+                selected_queue: Cell::new(0),
+                num_queues: Cell::new(1),
+                short_reads: Cell::new(false),
+                forced_status: Cell::new(None),
+                transient_errors: Cell::new(0),
+                max_discard_sectors: Cell::new(0),
+                features_ok_delay: Cell::new(0),
+                size_max: Cell::new(0),
+                seg_max: Cell::new(0),
+                queue_size_limit: Cell::new(u16::MAX),
+                queue_max_size: Cell::new(256),
+                config_updates: Cell::new(0),
+                generation: Cell::new(0),
+                writeback: Cell::new(1),
+                deferred: Cell::new(false),
+                blk_size: Cell::new(0),
+            }
+        }
+
+        // Carry out every request made available on any queue since the last
+        // call, in reverse order of submission if asked
+        unsafe fn complete(&self, reverse: bool) {
+            for queue in &self.queues {
+                if queue.size.get() != 0 {
+                    self.complete_queue(queue, reverse);
+                }
+            }
+        }
+
+        unsafe fn complete_queue(&self, queue: &FakeQueue, reverse: bool) {
+            let queue_size = usize::from(queue.size.get());
+            let descriptors = queue.descriptors.get() as *const Desc;
+            let avail = &*(queue.avail.get() as *const AvailRing<QUEUE_SIZE>);
+            let used = &mut *(queue.used.get() as *mut UsedRing<QUEUE_SIZE>);
+
+            let mut heads = Vec::new();
+            while queue.last_avail_idx.get() != core::ptr::read_volatile(&avail.idx) {
+                let idx = queue.last_avail_idx.get();
+                heads.push(avail.ring[usize::from(idx) % queue_size]);
+                queue.last_avail_idx.set(idx.wrapping_add(1));
+            }
+            if reverse {
+                heads.reverse();
+            }
+
+            for head in heads {
+                let mut chain = Vec::new();
+                let mut table = descriptors;
+                let mut d = &*table.add(usize::from(head));
+                if d.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                    assert_eq!(d.next, 0);
+                    table = d.addr as *const Desc;
+                    d = &*table;
+                }
+                chain.push(d);
+                while d.flags & VIRTQ_DESC_F_NEXT != 0 {
+                    d = &*table.add(usize::from(d.next));
+                    chain.push(d);
+                }
+                // Check the separate header, data and footer layout
+                assert_eq!(chain[0].flags & VIRTQ_DESC_F_WRITE, 0);
+                assert_eq!(chain[0].length, 16);
+                let footer = chain[chain.len() - 1];
+                assert_eq!(footer.flags, VIRTQ_DESC_F_WRITE);
+                assert_eq!(footer.length, 1);
+                assert_eq!(footer.next, 0);
+
+                let (status, len) = self.process(&chain);
+                let status = match self.transient_errors.get() {
+                    0 => self.forced_status.get().unwrap_or(status),
+                    n => {
+                        self.transient_errors.set(n - 1);
+                        super::VIRTIO_BLK_S_IOERR
+                    }
+                };
+                *(footer.addr as *mut u8) = status;
+
+                let elem = &mut used.ring[usize::from(used.idx) % queue_size];
+                elem.id = u32::from(head);
+                elem.len = len;
+                core::ptr::write_volatile(&mut used.idx, used.idx.wrapping_add(1));
+            }
+        }
+
+        fn queue(&self) -> &FakeQueue {
+            &self.queues[usize::from(self.selected_queue.get())]
+        }
+
+        // Carry out a single request, returning the status and the number of
+        // bytes written into the chain
+        unsafe fn process(&self, chain: &[&Desc]) -> (u8, u32) {
+            let header = &*(chain[0].addr as *const BlockRequestHeader);
+            let data = &chain[1..chain.len() - 1];
+            let mut disk = self.disk.borrow_mut();
+            let mut offset = header.sector as usize * 512;
+            let mut written = 0;
+            match header.request {
+                0 => {
+                    for d in data {
+                        let buf =
+                            core::slice::from_raw_parts_mut(d.addr as *mut u8, d.length as usize);
+                        buf.copy_from_slice(&disk[offset..offset + buf.len()]);
+                        offset += buf.len();
+                        written += d.length;
+                    }
+                    if self.short_reads.get() {
+                        written /= 2;
+                    }
+                }
+                1 => {
+                    for d in data {
+                        assert_eq!(d.flags & VIRTQ_DESC_F_WRITE, 0);
+                        let buf =
+                            core::slice::from_raw_parts(d.addr as *const u8, d.length as usize);
+                        disk[offset..offset + buf.len()].copy_from_slice(buf);
+                        offset += buf.len();
+                    }
+                }
+                4 => {}
+                8 => {
+                    let id = core::slice::from_raw_parts_mut(data[0].addr as *mut u8, 20);
+                    id.fill(0);
+                    id[..6].copy_from_slice(b"fake  ");
+                    written += 20;
+                }
+                11 | 13 => {
+                    let segment = &*(data[0].addr as *const DiscardWriteZeroesSegment);
+                    let start = segment.sector as usize * 512;
+                    let end = start + segment.num_sectors as usize * 512;
+                    disk[start..end].fill(0);
+                }
+                _ => return (2, 1),
+            }
+            (0, written + 1)
+        }
+    }
+
+    impl VirtioTransport for FakeTransport {
+        fn init(&mut self, _: u32) -> Result<(), VirtioError> {
+            Ok(())
+        }
+        fn get_status(&self) -> u32 {
+            let status = self.status.get();
+            match self.features_ok_delay.get() {
+                0 => status,
+                delay => {
+                    self.features_ok_delay.set(delay - 1);
+                    status & !VIRTIO_STATUS_FEATURES_OK
+                }
+            }
+        }
+        fn set_status(&self, status: u32) {
+            if status == 0 {
+                self.reset();
+            }
+            self.status.set(status)
+        }
+        fn add_status(&self, status: u32) {
+            self.status.set(self.status.get() | status)
+        }
+        fn reset(&self) {
+            self.status.set(0);
+            for q in &self.queues {
+                q.last_avail_idx.set(0);
+            }
+        }
+        fn get_features(&self) -> u64 {
+            self.features
+        }
+        fn set_features(&self, _: u64) {}
+        fn set_queue(&self, queue: u16) {
+            self.selected_queue.set(queue)
+        }
+        fn get_queue_max_size(&self) -> u16 {
+            self.queue_max_size.get()
+        }
+        fn set_queue_size(&self, queue_size: u16) {
+            self.queue()
+                .size
+                .set(queue_size.min(self.queue_size_limit.get()))
+        }
+        fn get_queue_size(&self) -> Option<u16> {
+            Some(self.queue().size.get())
+        }
+        fn set_descriptors_address(&self, address: u64) {
+            self.queue().descriptors.set(address)
+        }
+        fn set_avail_ring(&self, address: u64) {
+            self.queue().avail.set(address)
+        }
+        fn set_used_ring(&self, address: u64) {
+            self.queue().used.set(address)
+        }
+        fn set_queue_enable(&self) {}
+        fn notify_queue(&self, queue: u16) {
+            if !self.deferred.get() {
+                unsafe { self.complete_queue(&self.queues[usize::from(queue)], false) }
+            }
+        }
+        fn read_device_config(&self, offset: u64) -> u32 {
+            let capacity = (self.disk.borrow().len() / 512) as u64;
+            match offset {
+                0 => capacity as u32,
+                4 => {
+                    if self.config_updates.get() > 0 {
+                        self.config_updates.set(self.config_updates.get() - 1);
+                        self.generation.set(self.generation.get() + 1);
+                    }
+                    (capacity >> 32) as u32
+                }
+                8 => self.size_max.get(),
+                12 => self.seg_max.get(),
+                0x14 => self.blk_size.get(),
+                0x18 => 0x0008_0103,
+                0x1c => 128,
+                0x20 => u32::from(self.writeback.get()) | u32::from(self.num_queues.get()) << 16,
+                0x24 => self.max_discard_sectors.get(),
+                0x28 => 1,
+                _ => 0,
+            }
+        }
+        fn write_device_config_u8(&self, offset: u64, value: u8) {
+            assert_eq!(offset, 0x20);
+            self.writeback.set(value)
+        }
+        fn get_config_generation(&self) -> u32 {
+            self.generation.get()
+        }
+        fn ack_interrupt(&self) {}
+    }
+
+    // Device set up with the default options, ready for requests
+    fn init_device(transport: &mut FakeTransport) -> VirtioBlockDevice {
+        let mut d = VirtioBlockDevice::new(transport);
+        d.init().unwrap();
+        d
+    }
+
+    #[test]
+    fn test_cache_hits() {
+        let d = CachedBlockDevice::<_, 2>::new(MemBlockDevice::new(4));
+        let mut data = SectorBuf::new();
+
+        d.read(1, data.as_mut_bytes()).unwrap();
+        d.read(1, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[1; 512]);
+        d.read(2, data.as_mut_bytes()).unwrap();
+        d.read(1, data.as_mut_bytes()).unwrap();
+        assert_eq!(d.device.reads.get(), 2);
+
+        // Sector 2 is the least recently used so is evicted by sector 3
+        d.read(3, data.as_mut_bytes()).unwrap();
+        d.read(1, data.as_mut_bytes()).unwrap();
+        assert_eq!(d.device.reads.get(), 3);
+        d.read(2, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[2; 512]);
+        assert_eq!(d.device.reads.get(), 4);
+    }
+
+    #[test]
+    fn test_read_to_region() {
+        let d = MemBlockDevice::new(4);
+        let mut data = [0u8; 1024];
+        let mut region = MemoryRegion::from_bytes(&mut data);
+
+        d.read_to_region(2, &mut region).unwrap();
+        assert_eq!(d.reads.get(), 2);
+        assert_eq!(&data[..512], &[2; 512]);
+        assert_eq!(&data[512..], &[3; 512]);
+
+        let mut region = MemoryRegion::from_bytes(&mut data[..100]);
+        assert_eq!(
+            d.read_to_region(0, &mut region),
+            Err(Error::InvalidDataBufSize)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_cache_write_invalidates() {
+        let d = CachedBlockDevice::<_, 2>::new(MemBlockDevice::new(4));
+        let mut data = SectorBuf::new();
+
+        d.read(0, data.as_mut_bytes()).unwrap();
+        d.write(0, &[0xaa; 512]).unwrap();
+        d.read(0, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[0xaa; 512]);
+        assert_eq!(d.device.reads.get(), 2);
+
+        assert_eq!(d.read(0, &mut [0; 16]), Err(Error::InvalidDataBufSize));
+    }
+
+    #[test]
+    fn test_coalescing_reader() {
+        let d = CoalescingReader::<_, 4>::new(MemBlockDevice::new(6));
+        let mut data = SectorBuf::new();
+
+        // The second of two sequential reads triggers a read ahead
+        for sector in 0..2 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+        }
+        assert_eq!(d.device.reads.get(), 5);
+
+        for sector in 2..5 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[sector as u8; 512]);
+        }
+        assert_eq!(d.device.reads.get(), 5);
+
+        // Reading ahead from sector 5 runs off the end of the disk
+        d.read(5, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[5; 512]);
+
+        d.flush();
+        d.read(2, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[2; 512]);
+    }
+
+    #[test]
+    fn test_coalescing_reader_end() {
+        let d = CoalescingReader::<_, 4>::new(MemBlockDevice::new(8));
+        let mut data = SectorBuf::new();
+
+        // Once reading ahead has run off the end it is not retried for the
+        // rest of the run
+        for sector in 4..6 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+        }
+        let reads = d.device.reads.get();
+        for sector in 6..8 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[sector as u8; 512]);
+        }
+        assert_eq!(d.device.reads.get(), reads + 2);
+
+        // A new run reads ahead again
+        for sector in 0..2 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+        }
+        let reads = d.device.reads.get();
+        d.read(2, data.as_mut_bytes()).unwrap();
+        assert_eq!(d.device.reads.get(), reads);
+    }
+
+    #[test]
+    fn test_partition_bounds() {
+        let d = PartitionBlockDevice::new(MemBlockDevice::new(8), 2, 4);
+        let mut data = SectorBuf::new();
+        assert_eq!(d.sector_size(), SectorBuf::len());
+
+        d.read(0, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes()[0], 2);
+        d.read(3, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes()[0], 5);
+        assert_eq!(d.read(4, data.as_mut_bytes()), Err(Error::SectorOutOfRange));
+        #[cfg(feature = "block-write")]
+        assert_eq!(d.write(4, &[0; 512]), Err(Error::SectorOutOfRange));
+
+        let mut buf = [0u8; 1024];
+        let mut region = MemoryRegion::from_bytes(&mut buf);
+        assert_eq!(
+            d.read_to_region(3, &mut region),
+            Err(Error::SectorOutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_ram_disk() {
+        let mut image: Vec<u8> = (0..4u8).flat_map(|s| [s; 512]).collect();
+        let d = RamDisk::new(MemoryRegion::from_bytes(&mut image));
+        assert_eq!(d.sector_count(), 4);
+
+        let mut data = SectorBuf::new();
+        d.read(2, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[2; 512]);
+        assert_eq!(d.read(4, data.as_mut_bytes()), Err(Error::SectorOutOfRange));
+        assert_eq!(d.read(0, &mut [0; 16]), Err(Error::InvalidDataBufSize));
+
+        let mut buf = [0u8; 1024];
+        let mut region = MemoryRegion::from_bytes(&mut buf);
+        d.read_to_region(1, &mut region).unwrap();
+        assert_eq!(buf[..512], [1; 512]);
+        assert_eq!(buf[512..], [2; 512]);
+        let mut region = MemoryRegion::from_bytes(&mut buf);
+        assert_eq!(
+            d.read_to_region(3, &mut region),
+            Err(Error::SectorOutOfRange)
+        );
+
+        #[cfg(feature = "block-write")]
+        {
+            d.write(3, &[0xaa; 512]).unwrap();
+            assert_eq!(d.write(4, &[0; 512]), Err(Error::SectorOutOfRange));
+            d.read(3, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[0xaa; 512]);
+        }
+    }
+
+    #[test]
+    fn test_read_sector() {
+        let d = MemBlockDevice::new(4);
+        let mut buf = SectorBuf::new();
+        assert_eq!(&buf as *const _ as usize % 64, 0);
+
+        d.read_sector(2, &mut buf).unwrap();
+        assert_eq!(*buf, [2; 512]);
+        buf[0] = 0xaa;
+        assert_eq!(buf.as_bytes()[..2], [0xaa, 2]);
+    }
+
+    #[test]
+    fn test_sector_iter() {
+        let d = MemBlockDevice::new(4);
+
+        let first: Vec<u8> = d.sectors(1, 3).map(|s| s.unwrap().as_bytes()[0]).collect();
+        assert_eq!(first, [1, 2, 3]);
+
+        let results: Vec<_> = d.sectors(3, 4).map(|s| s.is_ok()).collect();
+        assert_eq!(results, [true, false]);
+    }
+
+    #[test]
+    fn test_try_boot() {
+        let mut devices = [
+            MemBlockDevice::new(1),
+            MemBlockDevice::new(4),
+            MemBlockDevice::new(8),
+        ];
+
+        // Pick the first device with at least four sectors
+        let mut data = SectorBuf::new();
+        let found = super::try_boot(&mut devices, |d| {
+            d.read(3, data.as_mut_bytes()).map(|_| d.reads.get())
+        });
+        assert_eq!(found, Some(1));
+        assert_eq!(devices[2].reads.get(), 0);
+
+        let found = super::try_boot(&mut devices, |d| d.read(8, data.as_mut_bytes()));
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_load_range() {
+        let d = MemBlockDevice::new(4);
+        let mut dest = [0xccu8; 1536];
+
+        super::load_range(&d, 1, 1100, dest.as_mut_ptr() as u64).unwrap();
+        assert_eq!(dest[..512], [1; 512]);
+        assert_eq!(dest[512..1024], [2; 512]);
+        assert_eq!(dest[1024..1100], [3; 76]);
+        assert!(dest[1100..].iter().all(|&b| b == 0xcc));
+
+        assert_eq!(
+            super::load_range(&d, 3, 600, dest.as_mut_ptr() as u64),
+            Err(Error::SectorOutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_read_runs() {
+        let d = MemBlockDevice::new(8);
+        let mut dest = [0u8; 2048];
+
+        super::read_runs(&d, &[(6, 1), (0, 0), (2, 3)], &mut dest).unwrap();
+        assert_eq!(dest[..512], [6; 512]);
+        assert_eq!(dest[512..1024], [2; 512]);
+        assert_eq!(dest[1536..], [4; 512]);
+
+        assert_eq!(
+            super::read_runs(&d, &[(0, 3)], &mut dest),
+            Err(Error::InvalidDataBufSize)
+        );
+        assert_eq!(
+            super::read_runs(&d, &[(0, 1), (7, 3)], &mut dest),
+            Err(Error::SectorOutOfRange)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_write_verify() {
+        struct LossyDisk(MemBlockDevice);
+
+        impl SectorRead for LossyDisk {
+            fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+                self.0.read(sector, data)
+            }
+        }
+
+        impl SectorWrite for LossyDisk {
+            fn write(&self, _: u64, _: &[u8]) -> Result<(), Error> {
+                Ok(())
+            }
+
+            fn flush(&self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let d = MemBlockDevice::new(2);
+        assert_eq!(d.write_verify(1, &[0x5a; 512]), Ok(()));
+
+        let d = LossyDisk(MemBlockDevice::new(2));
+        assert_eq!(
+            d.write_verify(1, &[0x5a; 512]),
+            Err(Error::WriteVerifyMismatch)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_read_write_dyn() {
+        let disk = MemBlockDevice::new(2);
+        let d: &dyn SectorReadWrite = &disk;
+        let mut data = SectorBuf::new();
+
+        d.write(1, &[0x55; 512]).unwrap();
+        d.flush().unwrap();
+        d.read(1, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[0x55; 512]);
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_virtio_requests() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+        assert_eq!(d.get_capacity(), 8);
+
+        let mut data = SectorBuf::new();
+        d.read(3, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[3; 512]);
+
+        d.write(3, &[0xaa; 512]).unwrap();
+        d.flush().unwrap();
+        let mut a = [0u8; 512];
+        let mut b = [0u8; 1024];
+        d.read_vectored(2, &mut [&mut a, &mut b]).unwrap();
+        assert_eq!(a, [2; 512]);
+        assert_eq!(&b[..512], &[0xaa; 512]);
+        assert_eq!(&b[512..], &[4; 512]);
+
+        assert_eq!(d.discard(0, 1), Err(Error::BlockNotSupported));
+        assert_eq!(d.read(8, data.as_mut_bytes()), Err(Error::SectorOutOfRange));
+        d.self_test().unwrap();
+        let mut scratch = d.scratch();
+        assert_eq!(scratch.as_bytes().as_ptr() as usize % 64, 0);
+        d.read(1, scratch.as_mut_bytes()).unwrap();
+        assert_eq!(scratch.as_bytes(), &[1; 512]);
+        let id = d.get_id().unwrap();
+        assert_eq!(id.len(), 4);
+        assert_eq!(&*id, b"fake");
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_virtio_discard() {
+        use super::{DiscardLimits, VIRTIO_BLK_F_DISCARD};
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_DISCARD);
+        transport.max_discard_sectors.set(3);
+        let d = init_device(&mut transport);
+        assert_eq!(
+            d.discard_limits(),
+            Some(DiscardLimits {
+                max_sectors: 3,
+                max_segments: 1,
+                sector_alignment: 0,
+            })
+        );
+
+        // Seven sectors take three requests of at most three sectors
+        let used_idx = d.used_idx();
+        d.discard(1, 7).unwrap();
+        assert_eq!(d.used_idx(), used_idx.wrapping_add(3));
+        let mut data = SectorBuf::new();
+        for sector in 1..8 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[0; 512]);
+        }
+        assert_eq!(d.discard(6, 3), Err(Error::SectorOutOfRange));
+        assert_eq!(d.discard(0, 0), Err(Error::SectorOutOfRange));
+
+        // No limit reported
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_DISCARD);
+        let d = init_device(&mut transport);
+        assert_eq!(d.discard_limits().unwrap().max_sectors, u32::MAX);
+        let used_idx = d.used_idx();
+        d.discard(0, 8).unwrap();
+        assert_eq!(d.used_idx(), used_idx.wrapping_add(1));
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_virtio_write_ordered() {
+        use super::VIRTIO_BLK_F_FLUSH;
+
+        // A flush request follows the write only if the device has a cache
+        for (features, requests) in [(0, 1), (VIRTIO_BLK_F_FLUSH, 2)] {
+            let mut transport = FakeTransport::new(8, features);
+            let d = init_device(&mut transport);
+
+            let used_idx = d.used_idx();
+            d.write_ordered(1, &[0xaa; 512]).unwrap();
+            assert_eq!(d.used_idx(), used_idx.wrapping_add(requests));
+
+            let mut data = SectorBuf::new();
+            d.read(1, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[0xaa; 512]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "block-write")]
+    fn test_virtio_write_from_region() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut buf: Vec<u8> = (0..3u8).flat_map(|s| [0xa0 | s; 512]).collect();
+        let region = MemoryRegion::from_bytes(&mut buf);
+        let used_idx = d.used_idx();
+        d.write_from_region(4, &region).unwrap();
+        assert_eq!(d.used_idx(), used_idx.wrapping_add(1));
+
+        let mut data = SectorBuf::new();
+        for s in 0..3 {
+            d.read(4 + s, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[0xa0 | s as u8; 512]);
+        }
+
+        let region = MemoryRegion::from_bytes(&mut buf[..700]);
+        assert_eq!(
+            d.write_from_region(0, &region),
+            Err(Error::InvalidDataBufSize)
+        );
+        let region = MemoryRegion::from_bytes(&mut buf);
+        assert_eq!(
+            d.write_from_region(6, &region),
+            Err(Error::SectorOutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_virtio_supported_ops() {
+        use super::{SupportedOps, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO};
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD);
+        let d = init_device(&mut transport);
+        let ops = d.supported_ops();
+        assert!(ops.contains(SupportedOps::READ | SupportedOps::GET_ID));
+        assert!(!ops.contains(SupportedOps::WRITE_ZEROES));
+        if cfg!(feature = "block-write") {
+            assert!(ops.contains(SupportedOps::WRITE | SupportedOps::FLUSH | SupportedOps::DISCARD));
+        }
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH);
+        let d = init_device(&mut transport);
+        assert_eq!(d.supported_ops(), SupportedOps::READ | SupportedOps::GET_ID);
+    }
+
+    #[test]
+    fn test_virtio_read_uninit() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut data = [core::mem::MaybeUninit::uninit(); 512];
+        assert_eq!(d.read_uninit(5, &mut data).unwrap(), &[5; 512]);
+        assert_eq!(
+            d.read_uninit(8, &mut data).map(|_| ()),
+            Err(Error::SectorOutOfRange)
+        );
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.short_reads.set(true);
+        let d = init_device(&mut transport);
+        assert_eq!(
+            d.read_uninit(5, &mut data).map(|_| ()),
+            Err(Error::ShortTransfer)
+        );
+    }
+
+    #[test]
+    fn test_virtio_topology() {
+        use super::{Topology, VIRTIO_BLK_F_TOPOLOGY};
+
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+        assert_eq!(d.topology(), None);
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_TOPOLOGY);
+        let d = init_device(&mut transport);
+        assert_eq!(
+            d.topology(),
+            Some(Topology {
+                physical_block_exp: 3,
+                alignment_offset: 1,
+                min_io_size: 8,
+                opt_io_size: 128,
+            })
+        );
+    }
+
+    #[test]
+    fn test_virtio_cache_mode() {
+        use super::{CacheMode, VIRTIO_BLK_F_CONFIG_WCE};
+
+        let mut transport = FakeTransport::new(8, 0);
+        let mut d = init_device(&mut transport);
+        assert_eq!(d.cache_mode(), CacheMode::WriteBack);
+        assert_eq!(
+            d.set_cache_mode(CacheMode::WriteThrough),
+            Err(Error::BlockNotSupported)
+        );
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_CONFIG_WCE);
+        let mut d = init_device(&mut transport);
+        assert_eq!(d.cache_mode(), CacheMode::WriteBack);
+        d.set_cache_mode(CacheMode::WriteThrough).unwrap();
+        assert_eq!(d.cache_mode(), CacheMode::WriteThrough);
+        d.set_cache_mode(CacheMode::WriteBack).unwrap();
+        assert_eq!(d.cache_mode(), CacheMode::WriteBack);
+    }
+
+    #[test]
+    fn test_virtio_multiqueue() {
+        use super::VIRTIO_BLK_F_MQ;
+
+        let mut data = SectorBuf::new();
+        let mut transport = FakeTransport::new(8, 0);
+        transport.num_queues.set(4);
+        let d = init_device(&mut transport);
+        assert_eq!(d.num_queues(), 1);
+        assert_eq!(
+            d.read_multi_on(1, 0, data.as_mut_bytes()),
+            Err(Error::InvalidQueue)
+        );
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_MQ);
+        transport.num_queues.set(4);
+        transport.deferred.set(true);
+        let transport_ptr = &transport as *const FakeTransport;
+        let d = init_device(&mut transport);
+        assert_eq!(d.num_queues(), 2);
+
+        // Requests on each queue are tracked separately
+        let (mut a, mut b) = ([0u8; 512], [0u8; 512]);
+        let ta = unsafe { d.submit_read_on(0, 2, &mut a) }.unwrap();
+        let tb = unsafe { d.submit_read_on(1, 5, &mut b) }.unwrap();
+        assert_eq!(ta.head, tb.head);
+        assert!(unsafe { d.submit_read_on(2, 0, data.as_mut_bytes()) }.is_err());
+        unsafe { (*transport_ptr).complete(false) };
+        d.wait(tb).unwrap();
+        d.wait(ta).unwrap();
+        assert_eq!(a, [2; 512]);
+        assert_eq!(b, [5; 512]);
+        assert_eq!(d.stats().completed, 2);
+        assert_eq!(d.free_descriptors(), 16);
+    }
+
+    #[test]
+    fn test_virtio_wait_async() {
+        use std::{
+            future::Future,
+            pin::Pin,
+            sync::Arc,
+            task::{Context, Poll, Wake, Waker},
+        };
+
+        struct CountingWaker(std::sync::atomic::AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.wake_by_ref()
+            }
+            fn wake_by_ref(self: &Arc<Self>) {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.deferred.set(true);
+        let transport_ptr = &transport as *const FakeTransport;
+        let d = init_device(&mut transport);
+
+        let wakes = Arc::new(CountingWaker(Default::default()));
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut data = [0u8; 1024];
+        let token = unsafe { d.submit_read(2, &mut data) }.unwrap();
+        let mut future = d.wait_async(token);
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        assert_eq!(wakes.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        unsafe { (*transport_ptr).complete(false) };
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(Ok(())));
+        drop(future);
+        assert_eq!(data[..512], [2; 512]);
+        assert_eq!(data[512..], [3; 512]);
+    }
+
+    #[test]
+    fn test_virtio_wait_async_drop() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut data = [0u8; 1024];
+        let token = unsafe { d.submit_read(2, &mut data) }.unwrap();
+        drop(d.wait_async(token));
+        assert_eq!(d.queues[0].borrow().queue.num_outstanding(), 0);
+        assert_eq!(data[..512], [2; 512]);
+    }
+
+    #[test]
+    fn test_virtio_read_sparse() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut data = [0xffu8; 1024];
+        d.read_sparse(6, &mut data).unwrap();
+        assert_eq!(data[..512], [6; 512]);
+        assert_eq!(data[512..], [7; 512]);
+        d.read_sparse(7, &mut data).unwrap();
+        assert_eq!(data[..512], [7; 512]);
+        assert_eq!(data[512..], [0; 512]);
+        data.fill(0xff);
+        d.read_sparse(u64::MAX, &mut data).unwrap();
+        assert_eq!(data, [0; 1024]);
+
+        assert_eq!(
+            d.read_sparse(9, &mut data[..100]),
+            Err(Error::InvalidDataBufSize)
+        );
+        assert_eq!(d.read_multi(7, &mut data), Err(Error::SectorOutOfRange));
+    }
+
+    #[test]
+    fn test_virtio_drain() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.deferred.set(true);
+        let transport_ptr = &transport as *const FakeTransport;
+        let mut d = init_device(&mut transport);
+        d.set_timeout(10);
+
+        let (mut a, mut b, mut c) = ([0u8; 512], [0u8; 512], [0u8; 512]);
+        let _ta = unsafe { d.submit_read(1, &mut a) }.unwrap();
+        let _tb = unsafe { d.submit_read(2, &mut b) }.unwrap();
+        unsafe { (*transport_ptr).complete(false) };
+        let _tc = unsafe { d.submit_read(3, &mut c) }.unwrap();
+
+        // The last request is still held by the device
+        assert_eq!(d.drain(), 2);
+        assert_eq!(d.free_descriptors(), 13);
+
+        unsafe {
+            (*transport_ptr).complete(false);
+            (*transport_ptr).deferred.set(false);
+        }
+        assert_eq!(d.drain(), 1);
+        assert_eq!(d.free_descriptors(), 16);
+        assert_eq!(d.drain(), 0);
+
+        let mut data = SectorBuf::new();
+        d.read(4, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[4; 512]);
+        assert_eq!(c, [3; 512]);
+    }
+
+    #[test]
+    fn test_virtio_out_of_order() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.deferred.set(true);
+        let transport_ptr = &transport as *const FakeTransport;
+        let d = init_device(&mut transport);
+
+        let (mut a, mut b, mut c) = ([0u8; 512], [0u8; 512], [0u8; 512]);
+        let ta = unsafe { d.submit_read(1, &mut a) }.unwrap();
+        let tb = unsafe { d.submit_read(2, &mut b) }.unwrap();
+        assert!(!d.poll(&ta).unwrap());
+
+        // The device returns the chains in reverse, with a later request
+        // still outstanding
+        unsafe { (*transport_ptr).complete(true) };
+        let tc = unsafe { d.submit_read(3, &mut c) }.unwrap();
+        assert!(!d.poll(&tc).unwrap());
+        assert!(d.poll(&ta).unwrap());
+        assert!(d.poll(&tb).unwrap());
+        assert!(!d.poll(&tc).unwrap());
+
+        unsafe { (*transport_ptr).complete(false) };
+        assert!(d.poll(&tc).unwrap());
+        assert_eq!((a, b, c), ([1; 512], [2; 512], [3; 512]));
+    }
+
+    #[test]
+    fn test_virtio_stats() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut a = [0u8; 512];
+        let mut b = [0u8; 1024];
+        d.read(1, &mut a).unwrap();
+        d.read_vectored(2, &mut [&mut a, &mut b]).unwrap();
+        assert_eq!(d.read(8, &mut a), Err(Error::SectorOutOfRange));
+        let t = unsafe { d.submit_read(4, &mut a) }.unwrap();
+        assert_eq!(
+            d.stats(),
+            BlockStats {
+                submitted: 3,
+                completed: 2,
+                bytes: 2048,
+            }
+        );
+
+        d.wait(t).unwrap();
+        assert_eq!(d.stats().completed, 3);
+        assert_eq!(d.stats().bytes, 2560);
+    }
+
+    #[test]
+    fn test_virtio_short_transfer() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.short_reads.set(true);
+        let d = init_device(&mut transport);
+
+        let mut data = SectorBuf::new();
+        assert_eq!(d.read(0, data.as_mut_bytes()), Err(Error::ShortTransfer));
+        #[cfg(feature = "block-write")]
+        d.write(0, &[0; 512]).unwrap();
+    }
+
+    #[test]
+    fn test_virtio_unknown_status() {
+        let mut data = SectorBuf::new();
+        let mut transport = FakeTransport::new(8, 0);
+        transport.forced_status.set(Some(2));
+        let d = init_device(&mut transport);
+        assert_eq!(
+            d.read(0, data.as_mut_bytes()),
+            Err(Error::BlockNotSupported)
+        );
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.forced_status.set(Some(0x42));
+        let d = init_device(&mut transport);
+        let err = d.read(0, data.as_mut_bytes()).unwrap_err();
+        assert_eq!(err, Error::UnknownBlockStatus(0x42));
+        assert_eq!(format!("{err}"), "unknown block status 0x42");
+    }
+
+    #[test]
+    fn test_virtio_read_retries() {
+        let mut data = SectorBuf::new();
+        let mut transport = FakeTransport::new(8, 0);
+        transport.transient_errors.set(2);
+        let mut d = init_device(&mut transport);
+        assert_eq!(d.read(1, data.as_mut_bytes()), Err(Error::BlockIO));
+
+        d.set_read_retries(2);
+        assert_eq!(d.read(1, data.as_mut_bytes()), Ok(()));
+        assert!(data.as_bytes().iter().all(|&b| b == 1));
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.transient_errors.set(3);
+        let mut d = init_device(&mut transport);
+        d.set_read_retries(2);
+        assert_eq!(d.read(1, data.as_mut_bytes()), Err(Error::BlockIO));
+    }
+
+    #[test]
+    fn test_virtio_submit_request() {
+        use super::{BlockOp, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP};
+
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut data = SectorBuf::new();
+        assert_eq!(
+            d.submit_request(BlockOp::In as u32, 3, Some(data.as_mut_bytes()), true),
+            Ok(VIRTIO_BLK_S_OK)
+        );
+        assert_eq!(data.as_bytes(), &[3; 512]);
+
+        // The status of requests the device does not know is passed back
+        // rather than turned into an error
+        assert_eq!(
+            d.submit_request(0x42, 0, None, false),
+            Ok(VIRTIO_BLK_S_UNSUPP)
+        );
+        assert_eq!(
+            d.submit_request(BlockOp::In as u32, 0, Some(&mut []), true),
+            Err(Error::InvalidDataBufSize)
+        );
+    }
+
+    #[test]
+    fn test_virtio_segment_limits() {
+        use super::{VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX};
+
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+        assert_eq!(d.max_segments(), 14);
+        assert_eq!(d.max_segment_size(), u32::MAX);
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_SIZE_MAX);
+        transport.seg_max.set(2);
+        transport.size_max.set(1024);
+        let d = init_device(&mut transport);
+        assert_eq!(d.max_segments(), 2);
+        assert_eq!(d.max_segment_size(), 1024);
+
+        let (mut a, mut b, mut c) = ([0u8; 512], [0u8; 1024], [0u8; 2048]);
+        d.read_vectored(1, &mut [&mut a, &mut b]).unwrap();
+        assert_eq!(b[..512], [2; 512]);
+        assert_eq!(
+            d.read_vectored(0, &mut [&mut a, &mut [0u8; 512], &mut b]),
+            Err(Error::RequestTooLarge)
+        );
+        assert_eq!(
+            d.read_vectored(0, &mut [&mut c]),
+            Err(Error::RequestTooLarge)
+        );
+
+        assert_eq!(d.read_multi(0, &mut c), Err(Error::RequestTooLarge));
+        d.read_large(3, &mut c).unwrap();
+        for (i, sector) in c.chunks(512).enumerate() {
+            assert_eq!(sector, [3 + i as u8; 512]);
+        }
+        assert_eq!(d.stats().completed, 3);
+    }
+
+    #[test]
+    fn test_virtio_read_large_small_segments() {
+        use super::{VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_SIZE_MAX};
+
+        // Segments smaller than a block are read in sectors, not blocks
+        let mut transport = FakeTransport::new(16, VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_SIZE_MAX);
+        transport.blk_size.set(4096);
+        transport.size_max.set(1024 + 100);
+        let d = init_device(&mut transport);
+        let mut data = [0u8; 4096];
+        d.read_large(0, &mut data).unwrap();
+        for (i, sector) in data.chunks(512).enumerate() {
+            assert_eq!(sector, [i as u8; 512]);
+        }
+        assert_eq!(d.stats().completed, 4);
+
+        // A failed single sector read is not issued again
+        let mut transport = FakeTransport::new(8, 0);
+        transport.short_reads.set(true);
+        let d = init_device(&mut transport);
+        let mut data = SectorBuf::new();
+        assert_eq!(
+            d.read_large(0, data.as_mut_bytes()),
+            Err(Error::ShortTransfer)
+        );
+        assert_eq!(d.stats().completed, 1);
+    }
+
+    #[test]
+    fn test_virtio_indirect() {
+        use crate::virtio::VIRTIO_F_RING_INDIRECT_DESC;
+
+        let mut transport = FakeTransport::new(32, VIRTIO_F_RING_INDIRECT_DESC);
+        let d = init_device(&mut transport);
+        assert_eq!(d.max_segments(), 30);
+
+        // More buffers than there are descriptors in the queue
+        let mut bufs = [[0u8; 512]; 20];
+        let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        d.read_vectored(4, &mut slices).unwrap();
+        for (i, b) in bufs.iter().enumerate() {
+            assert_eq!(b, &[4 + i as u8; 512]);
+        }
+        assert_eq!(d.free_descriptors(), 16);
+        assert!(!d.queues[0].borrow().indirect_in_use);
+
+        // Requests that fit are still placed directly in the queue
+        let mut a = [0u8; 512];
+        let t = unsafe { d.submit_read(1, &mut a) }.unwrap();
+        assert_eq!(
+            d.queues[0].borrow().queue.descriptor(t.head as u16).flags,
+            VIRTQ_DESC_F_NEXT
+        );
+        d.wait(t).unwrap();
+        assert_eq!(a, [1; 512]);
+    }
+
+    #[test]
+    fn test_virtio_queue_size_clamped() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.queue_size_limit.set(8);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(d.init(), Err(VirtioError::QueueTooSmall)));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_virtio_queue_unavailable() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.queue_max_size.set(0);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(d.init(), Err(VirtioError::QueueUnavailable)));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.queue_max_size.set(2);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(d.init(), Err(VirtioError::QueueTooSmall)));
+    }
+
+    #[test]
+    fn test_virtio_large_blocks() {
+        use super::VIRTIO_BLK_F_BLK_SIZE;
+
+        let mut transport = FakeTransport::new(16, VIRTIO_BLK_F_BLK_SIZE);
+        transport.blk_size.set(4096);
+        let d = init_device(&mut transport);
+        assert_eq!(d.block_size(), 4096);
+        assert_eq!(d.sector_size(), 4096);
+
+        // Requests are still made in 512-byte sectors
+        let mut data = SectorBuf::new();
+        d.read(3, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[3; 512]);
+        let mut data = [0u8; 1024];
+        d.read_multi(9, &mut data).unwrap();
+        assert_eq!(data[512..], [10; 512]);
+        assert_eq!(
+            d.read_multi(0, &mut data[..100]),
+            Err(Error::InvalidDataBufSize)
+        );
+    }
+
+    #[test]
+    fn test_virtio_builder() {
+        use super::VIRTIO_BLK_F_FLUSH;
+
+        let mut transport = FakeTransport::new(8, VIRTIO_BLK_F_FLUSH);
+        transport.deferred.set(true);
+        let mut d = VirtioBlockDevice::builder(&mut transport)
+            .timeout(10)
+            .max_queue(6)
+            .require_features(VIRTIO_BLK_F_FLUSH)
+            .init()
+            .unwrap();
+        assert_eq!(d.free_descriptors(), 4);
+        let mut data = SectorBuf::new();
+        assert_eq!(d.read(0, data.as_mut_bytes()), Err(Error::RequestTimeout));
+        // The device was reset and must be initialised again
+        assert!(!d.is_alive());
+        assert_eq!(d.read(0, data.as_mut_bytes()), Err(Error::DeviceNeedsReset));
+        d.init().unwrap();
+        assert!(d.is_alive());
+
+        let mut transport = FakeTransport::new(8, 0);
+        assert!(matches!(
+            VirtioBlockDevice::builder(&mut transport)
+                .require_features(VIRTIO_BLK_F_FLUSH)
+                .init(),
+            Err(VirtioError::FeatureNegotiationFailed)
+        ));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_virtio_capacity_generation() {
+        // The capacity is read again while the generation keeps changing
+        let mut transport = FakeTransport::new(8, 0);
+        transport.config_updates.set(3);
+        let d = init_device(&mut transport);
+        assert_eq!(d.get_capacity(), 8);
+        assert_eq!(transport.config_updates.get(), 0);
+        assert_eq!(transport.generation.get(), 3);
+
+        // A generation that never settles fails initialisation
+        let mut transport = FakeTransport::new(8, 0);
+        transport.config_updates.set(u32::MAX);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(d.init(), Err(VirtioError::ConfigUnstable)));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_virtio_queue_alignment() {
+        let transport = FakeTransport::new(8, 0);
+        assert!(matches!(
+            transport.setup_queue(0, 16, 3, 0x1008, 0x2000, 0x3000),
+            Err(VirtioError::MisalignedQueue)
+        ));
+        assert!(matches!(
+            transport.setup_queue(0, 16, 3, 0x1000, 0x2000, 0x3002),
+            Err(VirtioError::MisalignedQueue)
+        ));
+        assert_eq!(
+            transport.setup_queue(0, 16, 3, 0x1000, 0x2002, 0x3004).ok(),
+            Some(16)
+        );
+    }
+
+    #[test]
+    fn test_virtio_features_ok_retry() {
+        let mut transport = FakeTransport::new(8, 0);
+        transport.features_ok_delay.set(3);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(d.init().is_ok());
+
+        let mut transport = FakeTransport::new(8, 0);
+        transport.features_ok_delay.set(u32::MAX);
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(
+            d.init(),
+            Err(VirtioError::FeatureNegotiationFailed)
+        ));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_virtio_queue_full() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        // Each read takes three of the sixteen descriptors
+        let mut bufs = [[0u8; 512]; 6];
+        let (last, bufs) = bufs.split_last_mut().unwrap();
+        let tokens: Vec<_> = bufs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, b)| unsafe { d.submit_read(i as u64, b) }.unwrap())
+            .collect();
+        assert_eq!(d.free_descriptors(), 1);
+        assert!(matches!(
+            unsafe { d.submit_read(5, last) },
+            Err(Error::QueueFull)
+        ));
+
+        for t in tokens {
+            d.wait(t).unwrap();
+        }
+        assert_eq!(d.free_descriptors(), 16);
+        d.read(5, last).unwrap();
+        assert_eq!(last, &[5; 512]);
+        assert_eq!(bufs[4], [4; 512]);
+    }
+
+    #[test]
+    fn test_virtio_reinit() {
+        use super::VIRTIO_BLK_F_FLUSH;
+
+        let mut transport = FakeTransport::new(8, 0);
+        let d = VirtioBlockDevice::builder(&mut transport)
+            .require_features(VIRTIO_BLK_F_FLUSH)
+            .init();
+        assert!(matches!(d, Err(VirtioError::FeatureNegotiationFailed)));
+        assert_ne!(transport.status.get() & VIRTIO_STATUS_FAILED, 0);
+
+        // Retrying starts again from a reset device, even one that has been
+        // used since the rings were last set up
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        d.required_features = VIRTIO_BLK_F_FLUSH;
+        assert!(d.init().is_err());
+        d.required_features = 0;
+        d.init().unwrap();
+        let mut data = SectorBuf::new();
+        for sector in 0..3 {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+        }
+        d.init().unwrap();
+        assert_eq!(d.used_idx(), 0);
+        d.read(5, data.as_mut_bytes()).unwrap();
+        assert_eq!(data.as_bytes(), &[5; 512]);
+        assert_eq!(d.stats().completed, 1);
+    }
+
+    #[test]
+    fn test_virtio_is_alive() {
+        let mut transport = FakeTransport::new(8, 0);
+        let transport_ptr = &transport as *const FakeTransport;
+        let mut d = VirtioBlockDevice::new(&mut transport);
+        assert!(!d.is_alive());
+        d.init().unwrap();
+        assert!(d.is_alive());
+
+        let status = unsafe { &(*transport_ptr).status };
+        let running = status.get();
+        status.set(running | VIRTIO_STATUS_DEVICE_NEEDS_RESET);
+        assert!(!d.is_alive());
+        status.set(running | VIRTIO_STATUS_FAILED);
+        assert!(!d.is_alive());
+        status.set(0);
+        assert!(!d.is_alive());
+    }
+
+    #[test]
+    fn test_virtio_descriptor_layout() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        let mut a = [0u8; 1024];
+        let addr = a.as_ptr() as u64;
+        let t = unsafe { d.submit_read(2, &mut a) }.unwrap();
+        {
+            let state = d.queues[0].borrow();
+            let mut desc = state.queue.descriptor(t.head as u16);
+            let mut chain = vec![desc];
+            while desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+                desc = state.queue.descriptor(desc.next);
+                chain.push(desc);
+            }
+            let layout: Vec<_> = chain.iter().map(|d| (d.flags, d.length)).collect();
+            assert_eq!(
+                layout,
+                [
+                    (VIRTQ_DESC_F_NEXT, 16),
+                    (VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1024),
+                    (VIRTQ_DESC_F_WRITE, 1),
+                ]
+            );
+            assert_eq!(chain[1].addr, addr);
+        }
+        d.wait(t).unwrap();
+    }
+
+    #[test]
+    fn test_virtio_request_slots() {
+        let mut transport = FakeTransport::new(8, 0);
+        let d = init_device(&mut transport);
+
+        // Requests in flight together each point at their own header
+        let (mut a, mut b) = ([0u8; 512], [0u8; 512]);
+        let ta = unsafe { d.submit_read(1, &mut a) }.unwrap();
+        let tb = unsafe { d.submit_read(2, &mut b) }.unwrap();
+        {
+            let state = d.queues[0].borrow();
+            for (t, sector) in [(&ta, 1), (&tb, 2)] {
+                let header = &state.requests[t.head].header as *const _ as u64;
+                assert_eq!(state.queue.descriptor(t.head as u16).addr, header);
+                assert_eq!(state.requests[t.head].header.sector, sector);
+            }
+        }
+
+        d.wait(ta).unwrap();
+        d.wait(tb).unwrap();
+        assert_eq!(a, [1; 512]);
+        assert_eq!(b, [2; 512]);
     }
 }
@@ -139,6 +139,7 @@ pub extern "efiapi" fn read_blocks(
     Status::SUCCESS
 }
 
+#[cfg(feature = "block-write")]
 pub extern "efiapi" fn write_blocks(
     proto: *mut BlockIoProtocol,
     _: u32,
@@ -159,6 +160,9 @@ pub extern "efiapi" fn write_blocks(
         let block = unsafe { &*wrapper.block };
         match block.write(wrapper.start_lba + start + i as u64, data) {
             Ok(()) => continue,
+            Err(crate::block::Error::ReadOnlyDevice) => {
+                return Status::WRITE_PROTECTED;
+            }
             Err(_) => {
                 return Status::DEVICE_ERROR;
             }
@@ -168,6 +172,7 @@ pub extern "efiapi" fn write_blocks(
     Status::SUCCESS
 }
 
+#[cfg(feature = "block-write")]
 pub extern "efiapi" fn flush_blocks(proto: *mut BlockIoProtocol) -> Status {
     let wrapper = container_of!(proto, BlockWrapper, proto);
     let wrapper = unsafe { &*wrapper };
@@ -175,10 +180,28 @@ pub extern "efiapi" fn flush_blocks(proto: *mut BlockIoProtocol) -> Status {
     let block = unsafe { &*wrapper.block };
     match block.flush() {
         Ok(()) => Status::SUCCESS,
+        Err(crate::block::Error::ReadOnlyDevice) => Status::WRITE_PROTECTED,
         Err(_) => Status::DEVICE_ERROR,
     }
 }
 
+// Without the write path every device appears write protected
+#[cfg(not(feature = "block-write"))]
+pub extern "efiapi" fn write_blocks(
+    _: *mut BlockIoProtocol,
+    _: u32,
+    _: u64,
+    _: usize,
+    _: *mut c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+#[cfg(not(feature = "block-write"))]
+pub extern "efiapi" fn flush_blocks(_: *mut BlockIoProtocol) -> Status {
+    Status::WRITE_PROTECTED
+}
+
 impl<'a> BlockWrapper<'a> {
     pub fn new(
         block: *const crate::block::VirtioBlockDevice,
@@ -187,13 +187,16 @@ pub extern "efiapi" fn get_position(_: *mut FileProtocol, _: *mut u64) -> Status
 }
 
 pub extern "efiapi" fn set_position(file: *mut FileProtocol, position: u64) -> Status {
-    // Seeking to end of file is not supported
-    if position == 0xFFFFFFFFFFFFFFFF {
-        return Status::UNSUPPORTED;
-    }
     use crate::fat::Read;
     let wrapper = container_of_mut!(file, FileWrapper, proto);
-    match unsafe { (*wrapper).node.seek(position as u32) } {
+    let node = unsafe { &mut (*wrapper).node };
+    // Positions past the end of a file, including 0xFFFFFFFFFFFFFFFF which
+    // asks for the end, leave nothing more to read
+    let position = match node {
+        crate::fat::Node::File(f) => position.min(f.get_size().into()),
+        crate::fat::Node::Directory(_) => position,
+    };
+    match node.seek(u32::try_from(position).unwrap_or(u32::MAX)) {
         Err(crate::fat::Error::Unsupported) => Status::UNSUPPORTED,
         Err(_) => Status::DEVICE_ERROR,
         Ok(()) => Status::SUCCESS,
@@ -129,7 +129,6 @@ pub enum Error {
     Unsupported,
     NotFound,
     EndOfFile,
-    InvalidOffset,
     NodeTypeMismatch,
 }
 
@@ -258,6 +257,13 @@ impl<'a> Read for Node<'a> {
             Self::Directory(directory) => directory.seek(position),
         }
     }
+    fn position(&self) -> u32 {
+        match self {
+            Self::File(file) => file.position(),
+            // Directories are only read through their entries
+            Self::Directory(_) => 0,
+        }
+    }
     fn get_size(&self) -> u32 {
         match self {
             Self::File(file) => file.get_size(),
@@ -268,7 +274,9 @@ impl<'a> Read for Node<'a> {
 
 impl<'a> Directory<'a> {
     fn read_next(&mut self, data: &mut [u8]) -> Result<(), Error> {
-        assert_eq!(data.len(), SectorBuf::len());
+        if data.len() != SectorBuf::len() {
+            return Err(Error::Block(BlockError::InvalidDataBufSize));
+        }
 
         let sector = if self.cluster.is_some() {
             if self.sector >= self.filesystem.sectors_per_cluster {
@@ -453,30 +461,62 @@ impl<'a> Directory<'a> {
 pub trait Read {
     fn read(&mut self, data: &mut [u8]) -> Result<u32, Error>;
     fn seek(&mut self, offset: u32) -> Result<(), Error>;
+    fn position(&self) -> u32;
     fn get_size(&self) -> u32;
 
-    // Loads the remainder of the file into the specified memory region
-    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
-        let mut chunks = mem.as_bytes().chunks_exact_mut(SectorBuf::len());
-        for chunk in chunks.by_ref() {
-            self.read(chunk)?;
+    // Reads from the current position, following the cluster chain, until the
+    // end of the file or until `data` is full. Returns the number of bytes
+    // read, zero at the end of the file.
+    fn read_into(&mut self, data: &mut [u8]) -> Result<usize, Error> {
+        // Reads can return less than a sector, e.g. after seeking part way
+        // into one, so each continues from where the last one stopped
+        let mut total = 0;
+        while total < data.len() {
+            let rest = &mut data[total..];
+            let result = if rest.len() >= SectorBuf::len() {
+                self.read(&mut rest[..SectorBuf::len()])
+            } else {
+                // Use tmp buffer for last, partial sector and go back to
+                // just after the bytes copied so the next read gets the rest
+                let start = self.position();
+                let mut buf = SectorBuf::new();
+                let bytes = match self.read(buf.as_mut_bytes()) {
+                    Ok(bytes) => bytes as usize,
+                    Err(Error::EndOfFile) => break,
+                    Err(e) => return Err(e),
+                };
+                let copied = core::cmp::min(bytes, rest.len());
+                rest[..copied].copy_from_slice(&buf.as_bytes()[..copied]);
+                if copied < bytes {
+                    self.seek(start + copied as u32)?;
+                }
+                Ok(copied as u32)
+            };
+            match result {
+                Err(Error::EndOfFile) => break,
+                Err(e) => return Err(e),
+                Ok(bytes) => total += bytes as usize,
+            }
         }
-        let last = chunks.into_remainder();
-        if last.is_empty() {
-            return Ok(());
+        Ok(total)
+    }
+
+    // Loads the remainder of the file into the specified memory region, which
+    // the file must fill
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
+        let data = mem.as_bytes();
+        if self.read_into(data)? < data.len() {
+            return Err(Error::EndOfFile);
         }
-        // Use tmp buffer for last, partial sector
-        let mut dst = SectorBuf::new();
-        let bytes = self.read(dst.as_mut_bytes())? as usize;
-        assert_eq!(bytes, last.len());
-        last.copy_from_slice(&dst.as_bytes()[..bytes]);
         Ok(())
     }
 }
 
 impl<'a> Read for File<'a> {
     fn read(&mut self, data: &mut [u8]) -> Result<u32, Error> {
-        assert_eq!(data.len(), SectorBuf::len());
+        if data.len() != SectorBuf::len() {
+            return Err(Error::Block(BlockError::InvalidDataBufSize));
+        }
 
         if self.position >= self.size {
             return Err(Error::EndOfFile);
@@ -495,15 +535,29 @@ impl<'a> Read for File<'a> {
         }
 
         let cluster_start = self.filesystem.first_sector_of_cluster(self.active_cluster);
+        let sector = u64::from(cluster_start) + self.sector_offset;
+        let sector_size = SectorBuf::len() as u32;
 
-        match self
-            .filesystem
-            .read(u64::from(cluster_start) + self.sector_offset, data)
-        {
+        // After seeking into the middle of a sector only the rest of it is
+        // returned, at the start of data
+        let within = self.position % sector_size;
+        if within != 0 {
+            let mut buf = SectorBuf::new();
+            self.filesystem
+                .read(sector, buf.as_mut_bytes())
+                .map_err(Error::Block)?;
+            let bytes_read = core::cmp::min(sector_size - within, self.size - self.position);
+            data[..bytes_read as usize]
+                .copy_from_slice(&buf.as_bytes()[within as usize..(within + bytes_read) as usize]);
+            self.sector_offset += 1;
+            self.position += bytes_read;
+            return Ok(bytes_read);
+        }
+
+        match self.filesystem.read(sector, data) {
             Err(e) => Err(Error::Block(e)),
             Ok(()) => {
                 self.sector_offset += 1;
-                let sector_size = SectorBuf::len() as u32;
                 if (self.position + sector_size) > self.size {
                     let bytes_read = self.size - self.position;
                     self.position = self.size;
@@ -516,22 +570,28 @@ impl<'a> Read for File<'a> {
         }
     }
 
+    // Any byte offset within the file, or its end, may be sought to, the
+    // cluster chain is followed to the sector holding it
     fn seek(&mut self, position: u32) -> Result<(), Error> {
         let sector_size = SectorBuf::len() as u32;
-        if position % sector_size != 0 {
-            return Err(Error::InvalidOffset);
-        }
-
-        if position >= self.size {
+        if position > self.size {
             return Err(Error::EndOfFile);
         }
 
-        // Beyond, reset to zero and come back
-        if position < self.position {
+        // Beyond, reset to zero and come back. A read that ended part way into
+        // the last sector has already moved on from it so the position cannot
+        // be rounded down to find the current sector.
+        if position < self.position || self.position == self.size {
             self.position = 0;
             self.sector_offset = 0;
             self.active_cluster = self.start_cluster;
+        } else {
+            // Part way into a sector after a seek the cursor is still on that
+            // sector
+            self.position -= self.position % sector_size;
         }
+        let target = position;
+        let position = position - position % sector_size;
 
         // Like read but without reading, follow cluster chain if we reach end of
         // cluster
@@ -552,17 +612,21 @@ impl<'a> Read for File<'a> {
             self.position += sector_size;
         }
 
+        self.position = target;
         Ok(())
     }
+    fn position(&self) -> u32 {
+        self.position
+    }
     fn get_size(&self) -> u32 {
         self.size
     }
 }
 
 impl<'a> SectorRead for Filesystem<'a> {
-    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> crate::block::BlockResult<()> {
         if self.start + sector > self.last {
-            Err(crate::block::Error::BlockIO)
+            Err(crate::block::Error::SectorOutOfRange)
         } else {
             self.device.read(self.start + sector, data)
         }
@@ -924,6 +988,149 @@ mod tests {
         paths
     }
 
+    // Sector sized reads over a byte buffer mimicking a file
+    struct FakeFile {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for FakeFile {
+        fn read(&mut self, data: &mut [u8]) -> Result<u32, super::Error> {
+            if self.position >= self.data.len() {
+                return Err(super::Error::EndOfFile);
+            }
+            let end = self.data.len().min(self.position + data.len());
+            let bytes = end - self.position;
+            data[..bytes].copy_from_slice(&self.data[self.position..end]);
+            self.position += data.len();
+            Ok(bytes as u32)
+        }
+
+        fn seek(&mut self, position: u32) -> Result<(), super::Error> {
+            self.position = position as usize;
+            Ok(())
+        }
+
+        fn position(&self) -> u32 {
+            self.position as u32
+        }
+
+        fn get_size(&self) -> u32 {
+            self.data.len() as u32
+        }
+    }
+
+    #[test]
+    fn test_read_into() {
+        let mut f = FakeFile {
+            data: (0..1300).map(|i| i as u8).collect(),
+            position: 0,
+        };
+
+        // The partial sector at the end of the buffer is filled too
+        let mut data = [0u8; 1024 + 100];
+        assert_eq!(f.read_into(&mut data).unwrap(), 1024 + 100);
+        assert_eq!(data[1023], 1023u32 as u8);
+        assert_eq!(data[1123], 1123u32 as u8);
+        assert_eq!(f.read_into(&mut data).unwrap(), 1300 - 1124);
+        assert_eq!(data[0], 1124u32 as u8);
+        assert_eq!(data[1300 - 1124 - 1], 1299u32 as u8);
+        assert_eq!(f.read_into(&mut data).unwrap(), 0);
+
+        // Continues from where the last read stopped
+        let mut data = [0u8; 1024];
+        f.seek(0).unwrap();
+        assert_eq!(f.read_into(&mut data).unwrap(), 1024);
+        assert_eq!(f.read_into(&mut data).unwrap(), 1300 - 1024);
+        assert_eq!(data[0], 1024u32 as u8);
+        assert_eq!(f.read_into(&mut data).unwrap(), 0);
+    }
+
+    // Filesystem of one cluster per four sectors whose data starts at sector 0
+    // of the device and is not followed by a FAT
+    fn mem_filesystem(device: &dyn crate::block::SectorRead) -> super::Filesystem {
+        super::Filesystem {
+            device,
+            start: 0,
+            last: 7,
+            bytes_per_sector: 512,
+            sectors: 8,
+            fat_type: super::FatType::FAT16,
+            clusters: 2,
+            sectors_per_fat: 0,
+            sectors_per_cluster: 4,
+            fat_count: 0,
+            root_dir_sectors: 0,
+            first_fat_sector: 0,
+            first_data_sector: 0,
+            data_sector_count: 8,
+            data_cluster_count: 2,
+            root_cluster: 0,
+        }
+    }
+
+    #[test]
+    fn test_file_seek_after_eof() {
+        let device = crate::testing::MemBlockDevice::new(8);
+        let fs = mem_filesystem(&device);
+        let mut f = fs.get_file(2, 1300).unwrap();
+
+        let mut data = [0u8; 2048];
+        assert_eq!(f.read_into(&mut data).unwrap(), 1300);
+        assert_eq!(data[1299], 2);
+
+        // Back into the last sector from a position part way into it
+        let mut sector = SectorBuf::new();
+        f.seek(1100).unwrap();
+        assert_eq!(f.read(sector.as_mut_bytes()).unwrap(), 200);
+        assert_eq!(sector.as_bytes()[..200], [2; 200]);
+
+        f.seek(0).unwrap();
+        assert_eq!(f.read(sector.as_mut_bytes()).unwrap(), 512);
+        assert_eq!(sector.as_bytes()[0], 0);
+
+        f.seek(1300).unwrap();
+        assert_eq!(f.read(sector.as_mut_bytes()), Err(super::Error::EndOfFile));
+        assert_eq!(f.seek(1301), Err(super::Error::EndOfFile));
+    }
+
+    #[test]
+    fn test_file_read_into_partial_sector() {
+        let device = crate::testing::MemBlockDevice::new(8);
+        let fs = mem_filesystem(&device);
+        let mut f = fs.get_file(2, 1300).unwrap();
+
+        // The rest of the sector only partly copied is read next time
+        let mut data = [0u8; 700];
+        assert_eq!(f.read_into(&mut data).unwrap(), 700);
+        assert_eq!(data[699], 1);
+        assert_eq!(f.read_into(&mut data).unwrap(), 600);
+        assert_eq!(data[..324], [1; 324]);
+        assert_eq!(data[324..600], [2; 276]);
+    }
+
+    #[test]
+    fn test_load_file_after_unaligned_seek() {
+        let device = crate::testing::MemBlockDevice::new(8);
+        let fs = mem_filesystem(&device);
+        let mut f = fs.get_file(2, 1300).unwrap();
+
+        // The short read of the rest of the first sector leaves no gap
+        f.seek(500).unwrap();
+        let mut data = [0xffu8; 800];
+        let mut region = crate::mem::MemoryRegion::from_bytes(&mut data);
+        f.load_file(&mut region).unwrap();
+        assert_eq!(data[..12], [0; 12]);
+        assert_eq!(data[12..524], [1; 512]);
+        assert_eq!(data[524..], [2; 276]);
+
+        // Asking for more than is left fails rather than panicking
+        f.seek(500).unwrap();
+        let mut data = [0u8; 900];
+        let mut region = crate::mem::MemoryRegion::from_bytes(&mut data);
+        assert_eq!(f.load_file(&mut region), Err(super::Error::EndOfFile));
+    }
+
     #[test]
     fn test_fat_file_reads() {
         let images = fat_test_image_paths();
@@ -1038,6 +1245,16 @@ mod tests {
                         }
                         assert_eq!(bytes_so_far, f.size);
                     }
+
+                    // Part way into a sector only the rest of it is read
+                    if f.size >= 1024 {
+                        f.seek(700).expect("expect seek to work");
+                        let mut data = SectorBuf::new();
+                        assert_eq!(f.read(data.as_mut_bytes()).unwrap(), 1024 - 700);
+                        f.seek(100).expect("expect seek to work");
+                        assert_eq!(f.read(data.as_mut_bytes()).unwrap(), 512 - 100);
+                        assert_eq!(f.position, 512);
+                    }
                 }
             }
         }
@@ -1046,9 +1263,9 @@ mod tests {
     #[test]
     fn test_fat_init() {
         let d = FakeDisk::new(&clear_disk_path());
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
-                let mut f = crate::fat::Filesystem::new(&d, start, end);
+        match crate::part::find_esp(&d) {
+            Ok(esp) => {
+                let mut f = crate::fat::Filesystem::new(&d, esp.first_lba, esp.last_lba);
                 match f.init() {
                     Ok(()) => {
                         assert_eq!(f.sectors, 1_046_528);
@@ -1064,9 +1281,9 @@ mod tests {
     #[test]
     fn test_fat_open() {
         let d = FakeDisk::new(&clear_disk_path());
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
-                let mut f = crate::fat::Filesystem::new(&d, start, end);
+        match crate::part::find_esp(&d) {
+            Ok(esp) => {
+                let mut f = crate::fat::Filesystem::new(&d, esp.first_lba, esp.last_lba);
                 match f.init() {
                     Ok(()) => {
                         let file: crate::fat::File = f
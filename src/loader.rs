@@ -54,16 +54,7 @@ fn default_entry_pattern(f: &mut fat::File) -> Result<[u8; 260], fat::Error> {
     assert!(data.len() >= SectorBuf::len());
 
     let mut entry_pattern = [0; 260];
-    let mut offset = 0;
-    loop {
-        match f.read(&mut data[offset..offset + SectorBuf::len()]) {
-            Err(fat::Error::EndOfFile) => break,
-            Err(e) => return Err(e),
-            Ok(_) => {
-                offset += SectorBuf::len();
-            }
-        }
-    }
+    f.read_into(&mut data)?;
 
     let conf = unsafe { core::str::from_utf8_unchecked(&data) };
     for line in conf.lines() {
@@ -169,16 +160,7 @@ fn parse_entry(f: &mut fat::File) -> Result<LoaderConfig, fat::Error> {
 
     let mut loader_config: LoaderConfig = unsafe { core::mem::zeroed() };
 
-    let mut offset = 0;
-    loop {
-        match f.read(&mut data[offset..offset + SectorBuf::len()]) {
-            Err(fat::Error::EndOfFile) => break,
-            Err(e) => return Err(e),
-            Ok(_) => {
-                offset += SectorBuf::len();
-            }
-        }
-    }
+    f.read_into(&mut data)?;
 
     let conf = unsafe { core::str::from_utf8_unchecked(&data) };
     for line in conf.lines() {
@@ -259,8 +241,8 @@ mod tests {
     #[test]
     fn test_default_entry() {
         let d = FakeDisk::new(&clear_disk_path());
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
-        let mut fs = crate::fat::Filesystem::new(&d, start, end);
+        let esp = crate::part::find_esp(&d).unwrap();
+        let mut fs = crate::fat::Filesystem::new(&d, esp.first_lba, esp.last_lba);
         fs.init().expect("Error initialising filesystem");
 
         let mut f: crate::fat::File = fs.open("/loader/loader.conf").unwrap().try_into().unwrap();
@@ -51,6 +51,7 @@ mod fdt;
 mod integration;
 mod layout;
 mod loader;
+mod mbr;
 mod mem;
 mod part;
 mod pci;
@@ -62,11 +63,14 @@ mod rtc;
 mod rtc_goldfish;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
+#[cfg(test)]
+mod testing;
 #[cfg(target_arch = "riscv64")]
 mod uart_mmio;
 #[cfg(target_arch = "aarch64")]
 mod uart_pl011;
 mod virtio;
+mod virtio_mmio;
 
 #[cfg(all(not(test), feature = "log-panic"))]
 #[panic_handler]
@@ -92,18 +96,23 @@ fn boot_from_device(device: &mut block::VirtioBlockDevice, info: &dyn bootinfo::
         log!("Error configuring block device: {:?}", err);
         return false;
     }
+    let capacity = device.get_capacity();
     log!(
         "Virtio block device configured. Capacity: {} sectors",
-        device.get_capacity()
+        capacity
     );
 
-    let (start, end) = match part::find_efi_partition(device) {
-        Ok(p) => p,
+    let (start, end) = match part::find_esp(device) {
+        Ok(p) => (p.first_lba, p.last_lba),
         Err(err) => {
             log!("Failed to find EFI partition: {:?}", err);
             return false;
         }
     };
+    if start > end || end >= capacity {
+        log!("EFI partition extends beyond the end of the device");
+        return false;
+    }
     log!("Found EFI partition");
 
     let mut f = fat::Filesystem::new(device, start, end);
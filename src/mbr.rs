@@ -0,0 +1,108 @@
+// Copyright © 2019 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::block::{Error as BlockError, SectorBuf, SectorRead};
+
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+const SIGNATURE_OFFSET: usize = 510;
+
+/// Partition type used by the protective MBR of a GPT disk
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Primary partition from the MBR
+pub struct PartitionEntry {
+    pub status: u8,
+    pub partition_type: u8,
+    pub start_lba: u32,
+    pub sector_count: u32,
+}
+
+impl PartitionEntry {
+    fn parse(entry: &[u8]) -> PartitionEntry {
+        // Skip the CHS addresses at 1-3 and 5-7, they are unused with LBA
+        PartitionEntry {
+            status: entry[0],
+            partition_type: entry[4],
+            start_lba: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            sector_count: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Block(BlockError),
+    InvalidPartitionTable,
+}
+
+/// Read the four primary partition entries from the MBR in sector 0
+pub fn read_partitions<T: SectorRead + ?Sized>(device: &T) -> Result<[PartitionEntry; 4], Error> {
+    let mut data = SectorBuf::new();
+    device.read(0, data.as_mut_bytes()).map_err(Error::Block)?;
+
+    let data = data.as_bytes();
+    if data[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    let mut parts = [PartitionEntry::default(); 4];
+    for (i, p) in parts.iter_mut().enumerate() {
+        let offset = PARTITION_TABLE_OFFSET + i * PARTITION_ENTRY_SIZE;
+        *p = PartitionEntry::parse(&data[offset..offset + PARTITION_ENTRY_SIZE]);
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::SectorBuf, testing::MemBlockDevice};
+
+    fn mbr_with_entry(entry: [u8; 16]) -> MemBlockDevice {
+        let mut mbr = [0u8; SectorBuf::len()];
+        mbr[446..462].copy_from_slice(&entry);
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        MemBlockDevice::from_bytes(mbr.to_vec())
+    }
+
+    #[test]
+    fn test_read_partitions() {
+        let d = mbr_with_entry([
+            0x80, 0, 0, 0, 0x0c, 0, 0, 0, 0x00, 0x08, 0, 0, 0x00, 0x00, 0x10, 0,
+        ]);
+
+        let parts = super::read_partitions(&d).unwrap();
+        assert_eq!(parts[0].status, 0x80);
+        assert_eq!(parts[0].partition_type, 0x0c);
+        assert_eq!(parts[0].start_lba, 2048);
+        assert_eq!(parts[0].sector_count, 0x10_0000);
+        assert!(parts[1..]
+            .iter()
+            .all(|p| *p == super::PartitionEntry::default()));
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let mut d = mbr_with_entry([0; 16]);
+        d.as_mut_bytes()[511] = 0;
+
+        assert!(matches!(
+            super::read_partitions(&d),
+            Err(super::Error::InvalidPartitionTable)
+        ));
+    }
+}
@@ -26,6 +26,15 @@ impl MemoryRegion {
         MemoryRegion { base, length }
     }
 
+    /// Region covering a PCI memory BAR, `None` if the BAR has not been
+    /// assigned an address or has no size
+    pub fn from_bar(base: u64, size: usize) -> Option<MemoryRegion> {
+        if base == 0 || size == 0 {
+            return None;
+        }
+        Some(MemoryRegion::new(base, size as u64))
+    }
+
     /// Take a slice and turn it into a region of memory
     pub fn from_bytes(data: &mut [u8]) -> MemoryRegion {
         MemoryRegion {
@@ -34,14 +43,34 @@ impl MemoryRegion {
         }
     }
 
+    /// Start address of the region
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Length of the region in bytes
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
     // Expose the entire region as a byte slice
     pub fn as_bytes(&mut self) -> &mut [u8] {
         self.as_mut_slice(0, self.length)
     }
 
-    /// Expose a section of the memory region as a slice
+    /// Expose a section of the memory region as a slice of `length` elements
+    /// starting `offset` bytes in. Accesses through the slice are not
+    /// volatile so this is only suitable for plain memory, not MMIO.
+    pub fn as_slice<T>(&self, offset: u64, length: u64) -> &[T] {
+        assert!(self.contains_slice::<T>(offset, length));
+        unsafe { core::slice::from_raw_parts((self.base + offset) as *const T, length as usize) }
+    }
+
+    /// Expose a section of the memory region as a mutable slice of `length`
+    /// elements starting `offset` bytes in. Accesses through the slice are
+    /// not volatile so this is only suitable for plain memory, not MMIO.
     pub fn as_mut_slice<T>(&mut self, offset: u64, length: u64) -> &mut [T] {
-        assert!((offset + (length * core::mem::size_of::<T>() as u64)) <= self.length);
+        assert!(self.contains_slice::<T>(offset, length));
         unsafe { core::slice::from_raw_parts_mut((self.base + offset) as *mut T, length as usize) }
     }
 
@@ -50,7 +79,7 @@ impl MemoryRegion {
     where
         T: Copy,
     {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
+        assert!(self.contains::<T>(offset));
         unsafe { *((self.base + offset) as *const T) }
     }
 
@@ -76,7 +105,7 @@ impl MemoryRegion {
 
     /// Write a value at the given offset
     pub fn write<T>(&self, offset: u64, value: T) {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
+        assert!(self.contains::<T>(offset));
         unsafe {
             *((self.base + offset) as *mut T) = value;
         }
@@ -99,15 +128,12 @@ impl MemoryRegion {
 
     /// Write a single qword at given offset
     pub fn write_u64(&self, offset: u64, value: u64) {
-        assert!(offset + 7 < self.length);
-        unsafe {
-            *((self.base + offset) as *mut u64) = value;
-        }
+        self.write(offset, value)
     }
 
     /// Read a value at given offset with a mechanism suitable for MMIO
     fn io_read<T>(&self, offset: u64) -> T {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
+        assert!(self.contains::<T>(offset));
         unsafe { core::ptr::read_volatile((self.base + offset) as *const T) }
     }
 
@@ -131,9 +157,62 @@ impl MemoryRegion {
         self.io_read(offset)
     }
 
+    // Whether a value of type T at the given offset fits inside the region
+    fn contains<T>(&self, offset: u64) -> bool {
+        match offset.checked_add(core::mem::size_of::<T>() as u64) {
+            Some(end) => end <= self.length,
+            None => false,
+        }
+    }
+
+    // Whether `length` values of type T at the given offset fit inside the
+    // region
+    fn contains_slice<T>(&self, offset: u64, length: u64) -> bool {
+        match length
+            .checked_mul(core::mem::size_of::<T>() as u64)
+            .and_then(|size| size.checked_add(offset))
+        {
+            Some(end) => end <= self.length,
+            None => false,
+        }
+    }
+
+    /// Read a value at given offset with a mechanism suitable for MMIO,
+    /// returning `None` if the access would be outside of the region
+    fn try_io_read<T>(&self, offset: u64) -> Option<T> {
+        if !self.contains::<T>(offset) {
+            return None;
+        }
+        Some(self.io_read(offset))
+    }
+
+    /// Read a single byte at given offset with a mechanism suitable for MMIO,
+    /// returning `None` if the access would be outside of the region
+    pub fn try_io_read_u8(&self, offset: u64) -> Option<u8> {
+        self.try_io_read(offset)
+    }
+
+    /// Read a single word at given offset with a mechanism suitable for MMIO,
+    /// returning `None` if the access would be outside of the region
+    pub fn try_io_read_u16(&self, offset: u64) -> Option<u16> {
+        self.try_io_read(offset)
+    }
+
+    /// Read a single dword at given offset with a mechanism suitable for MMIO,
+    /// returning `None` if the access would be outside of the region
+    pub fn try_io_read_u32(&self, offset: u64) -> Option<u32> {
+        self.try_io_read(offset)
+    }
+
+    /// Read a single qword at given offset with a mechanism suitable for MMIO,
+    /// returning `None` if the access would be outside of the region
+    pub fn try_io_read_u64(&self, offset: u64) -> Option<u64> {
+        self.try_io_read(offset)
+    }
+
     /// Write a value at given offset using a mechanism suitable for MMIO
     fn io_write<T>(&self, offset: u64, value: T) {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
+        assert!(self.contains::<T>(offset));
         unsafe {
             core::ptr::write_volatile((self.base + offset) as *mut T, value);
         }
@@ -158,4 +237,141 @@ impl MemoryRegion {
     pub fn io_write_u64(&self, offset: u64, value: u64) {
         self.io_write(offset, value)
     }
+
+    /// Read a little-endian word at given offset with a mechanism suitable
+    /// for MMIO, converting it to the byte order of the CPU
+    pub fn io_read_le_u16(&self, offset: u64) -> u16 {
+        u16::from_le(self.io_read(offset))
+    }
+
+    /// Read a little-endian dword at given offset with a mechanism suitable
+    /// for MMIO, converting it to the byte order of the CPU
+    pub fn io_read_le_u32(&self, offset: u64) -> u32 {
+        u32::from_le(self.io_read(offset))
+    }
+
+    /// Read a little-endian qword at given offset with a mechanism suitable
+    /// for MMIO, converting it to the byte order of the CPU
+    pub fn io_read_le_u64(&self, offset: u64) -> u64 {
+        u64::from_le(self.io_read(offset))
+    }
+
+    /// Write a word in little-endian byte order at given offset with a
+    /// mechanism suitable for MMIO
+    pub fn io_write_le_u16(&self, offset: u64, value: u16) {
+        self.io_write(offset, value.to_le())
+    }
+
+    /// Write a dword in little-endian byte order at given offset with a
+    /// mechanism suitable for MMIO
+    pub fn io_write_le_u32(&self, offset: u64, value: u32) {
+        self.io_write(offset, value.to_le())
+    }
+
+    /// Write a qword in little-endian byte order at given offset with a
+    /// mechanism suitable for MMIO
+    pub fn io_write_le_u64(&self, offset: u64, value: u64) {
+        self.io_write(offset, value.to_le())
+    }
+
+    /// Write a value at given offset using a mechanism suitable for MMIO,
+    /// returning whether the access was inside of the region
+    fn try_io_write<T>(&self, offset: u64, value: T) -> bool {
+        if !self.contains::<T>(offset) {
+            return false;
+        }
+        self.io_write(offset, value);
+        true
+    }
+
+    /// Write a single byte at given offset with a mechanism suitable for MMIO,
+    /// returning whether the access was inside of the region
+    pub fn try_io_write_u8(&self, offset: u64, value: u8) -> bool {
+        self.try_io_write(offset, value)
+    }
+
+    /// Write a single word at given offset with a mechanism suitable for MMIO,
+    /// returning whether the access was inside of the region
+    pub fn try_io_write_u16(&self, offset: u64, value: u16) -> bool {
+        self.try_io_write(offset, value)
+    }
+
+    /// Write a single dword at given offset with a mechanism suitable for MMIO,
+    /// returning whether the access was inside of the region
+    pub fn try_io_write_u32(&self, offset: u64, value: u32) -> bool {
+        self.try_io_write(offset, value)
+    }
+
+    /// Write a single qword at given offset with a mechanism suitable for MMIO,
+    /// returning whether the access was inside of the region
+    pub fn try_io_write_u64(&self, offset: u64, value: u64) -> bool {
+        self.try_io_write(offset, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryRegion;
+
+    #[test]
+    fn test_from_bar() {
+        let region = MemoryRegion::from_bar(0xfe00_0000, 0x4000).unwrap();
+        assert_eq!(region.base(), 0xfe00_0000);
+        assert_eq!(region.length(), 0x4000);
+
+        assert!(MemoryRegion::from_bar(0, 0x4000).is_none());
+        assert!(MemoryRegion::from_bar(0xfe00_0000, 0).is_none());
+        assert_eq!(
+            MemoryRegion::from_bar(0xfe00_0000, 0x20).unwrap().length(),
+            0x20
+        );
+    }
+
+    #[test]
+    fn test_checked_io_access() {
+        let mut data = [0u8; 8];
+        let region = MemoryRegion::from_bytes(&mut data);
+
+        assert!(region.try_io_write_u32(4, 0x1234_5678));
+        assert_eq!(region.try_io_read_u32(4), Some(0x1234_5678));
+        assert!(!region.try_io_write_u32(5, 0));
+        assert_eq!(region.try_io_read_u32(8), None);
+        assert_eq!(region.try_io_read_u32(u64::MAX), None);
+
+        assert!(region.try_io_write_u64(0, 0x0102_0304_0506_0708));
+        assert_eq!(region.try_io_read_u16(6), Some(0x0102));
+        assert_eq!(region.try_io_read_u8(7), Some(0x01));
+        assert!(!region.try_io_write_u16(7, 0));
+        assert!(region.try_io_write_u8(7, 0xff));
+        assert_eq!(region.try_io_read_u64(1), None);
+        assert_eq!(region.try_io_read_u64(0), Some(0xff02_0304_0506_0708));
+    }
+
+    #[test]
+    fn test_little_endian_io() {
+        let mut data = [0x78u8, 0x56, 0x34, 0x12, 0, 0, 0, 0];
+        let region = MemoryRegion::from_bytes(&mut data);
+
+        assert_eq!(region.io_read_le_u32(0), 0x1234_5678);
+        assert_eq!(region.io_read_le_u16(2), 0x1234);
+        region.io_write_le_u16(4, 0xabcd);
+        assert_eq!(region.io_read_le_u64(0), 0xabcd_1234_5678);
+        region.io_write_le_u64(0, 0x0102_0304_0506_0708);
+        region.io_write_le_u32(0, 0x1122_3344);
+        assert_eq!(data, [0x44, 0x33, 0x22, 0x11, 0x04, 0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn test_slice_access() {
+        // Backed by a u64 so that the wider slices are aligned
+        let mut data = 0u64;
+        let mut region = MemoryRegion::new(&mut data as *mut u64 as u64, 8);
+
+        region.as_mut_slice::<u16>(2, 3).copy_from_slice(&[1, 2, 3]);
+        assert_eq!(region.as_slice::<u8>(2, 6), [1, 0, 2, 0, 3, 0]);
+        assert_eq!(region.as_slice::<u32>(4, 1), [0x0003_0002]);
+        assert!(region.as_slice::<u64>(8, 0).is_empty());
+        assert!(!region.contains_slice::<u16>(4, 3));
+        assert!(!region.contains_slice::<u64>(0, u64::MAX));
+    }
 }
@@ -12,15 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::block::{Error as BlockError, SectorBuf, SectorRead};
+use crate::{
+    block::{Error as BlockError, SectorBuf, SectorRead},
+    mbr,
+};
 
 #[repr(packed)]
 /// GPT header
 struct Header {
     signature: u64,
     _revision: u32,
-    _header_size: u32,
-    _header_crc: u32,
+    header_size: u32,
+    header_crc: u32,
     _reserved: u32,
     _current_lba: u64,
     _backup_lba: u64,
@@ -59,16 +62,60 @@ impl PartitionEntry {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// First and last LBA (inclusive) of a partition
+pub struct PartitionRange {
+    pub first_lba: u64,
+    pub last_lba: u64,
+}
+
 #[derive(Debug)]
 pub enum Error {
     Block(BlockError),
+    NoProtectiveMbr,
     HeaderNotFound,
+    InvalidHeaderChecksum,
     ViolatesSpecification,
     ExceededPartitionCount,
     NoEFIPartition,
 }
 
+// CRC32 (IEEE 802.3) as used by the GPT header. Computed bitwise as it is
+// only run over a single sector.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn check_protective_mbr(r: &dyn SectorRead) -> Result<(), Error> {
+    let parts = mbr::read_partitions(r).map_err(|e| match e {
+        mbr::Error::Block(e) => Error::Block(e),
+        mbr::Error::InvalidPartitionTable => Error::NoProtectiveMbr,
+    })?;
+
+    if parts
+        .iter()
+        .any(|p| p.partition_type == mbr::TYPE_GPT_PROTECTIVE)
+    {
+        Ok(())
+    } else {
+        Err(Error::NoProtectiveMbr)
+    }
+}
+
 pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> Result<u32, Error> {
+    check_protective_mbr(r)?;
+
     let mut data = SectorBuf::new();
     match r.read(1, data.as_mut_bytes()) {
         Ok(_) => {}
@@ -83,6 +130,19 @@ pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> R
         return Err(Error::HeaderNotFound);
     }
 
+    // The checksum covers header_size bytes with the checksum field zeroed
+    let header_size = h.header_size as usize;
+    if header_size < core::mem::size_of::<Header>() || header_size > SectorBuf::len() {
+        return Err(Error::ViolatesSpecification);
+    }
+    let header_crc = h.header_crc;
+    let mut header = [0u8; SectorBuf::len()];
+    header[..header_size].copy_from_slice(&data.as_bytes()[..header_size]);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Err(Error::InvalidHeaderChecksum);
+    }
+
     if h.first_usable_lba < 34 {
         return Err(Error::ViolatesSpecification);
     }
@@ -110,7 +170,10 @@ pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> R
             if p.guid == [0; 16] {
                 continue;
             }
-            parts_out[current_part as usize] = *p;
+            let out = parts_out
+                .get_mut(current_part as usize)
+                .ok_or(Error::ExceededPartitionCount)?;
+            *out = *p;
             current_part += 1;
         }
 
@@ -123,23 +186,21 @@ pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> R
     Ok(current_part)
 }
 
-/// Find EFI partition
-pub fn find_efi_partition(r: &dyn SectorRead) -> Result<(u64, u64), Error> {
+/// Find the EFI System Partition
+pub fn find_esp(r: &dyn SectorRead) -> Result<PartitionRange, Error> {
     // Assume no more than 16 partitions on the disk
     let mut parts: [PartitionEntry; 16] = unsafe { core::mem::zeroed() };
 
     let part_count = get_partitions(r, &mut parts)? as usize;
 
-    for (checked_part_count, p) in (parts[0..part_count]).iter().enumerate() {
-        if p.is_efi_partition() {
-            return Ok((p.first_lba, p.last_lba));
-        }
-        if checked_part_count == part_count {
-            return Err(Error::ExceededPartitionCount);
-        }
-    }
-
-    Err(Error::NoEFIPartition)
+    parts[0..part_count]
+        .iter()
+        .find(|p| p.is_efi_partition())
+        .map(|p| PartitionRange {
+            first_lba: p.first_lba,
+            last_lba: p.last_lba,
+        })
+        .ok_or(Error::NoEFIPartition)
 }
 
 #[cfg(test)]
@@ -155,7 +216,10 @@ pub mod tests {
     use std::path::{Path, PathBuf};
 
     use crate::block;
-    use crate::block::{SectorBuf, SectorRead};
+    use crate::{
+        block::{SectorBuf, SectorRead},
+        testing::MemBlockDevice,
+    };
 
     pub struct FakeDisk {
         file: RefCell<File>,
@@ -178,7 +242,7 @@ pub mod tests {
     }
 
     impl SectorRead for FakeDisk {
-        fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+        fn read(&self, sector: u64, data: &mut [u8]) -> block::BlockResult<()> {
             let mut file = self.file.borrow_mut();
             match file.seek(SeekFrom::Start(sector * SectorBuf::len() as u64)) {
                 Ok(_) => {}
@@ -201,13 +265,73 @@ pub mod tests {
     }
 
     #[test]
-    fn test_find_efi_partition() {
+    fn test_crc32() {
+        assert_eq!(super::crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(super::crc32(&[]), 0);
+    }
+
+    // Protective MBR, GPT header and a single ESP entry spanning LBAs 34-99
+    fn gpt_disk() -> MemBlockDevice {
+        let mut sectors = vec![[0u8; 512]; 34];
+
+        sectors[0][446 + 4] = 0xee;
+        sectors[0][510] = 0x55;
+        sectors[0][511] = 0xaa;
+
+        let h = &mut sectors[1];
+        h[0..8].copy_from_slice(b"EFI PART");
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[40..48].copy_from_slice(&34u64.to_le_bytes());
+        h[72..80].copy_from_slice(&2u64.to_le_bytes());
+        h[80..84].copy_from_slice(&128u32.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let crc = super::crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        let p = &mut sectors[2];
+        p[0..16].copy_from_slice(&[
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ]);
+        p[16] = 1;
+        p[32..40].copy_from_slice(&34u64.to_le_bytes());
+        p[40..48].copy_from_slice(&99u64.to_le_bytes());
+
+        MemBlockDevice::from_bytes(sectors.concat())
+    }
+
+    #[test]
+    fn test_find_esp_in_memory() {
+        let mut d = gpt_disk();
+        assert_eq!(
+            super::find_esp(&d).unwrap(),
+            super::PartitionRange {
+                first_lba: 34,
+                last_lba: 99
+            }
+        );
+
+        d.as_mut_bytes()[512 + 50] ^= 1;
+        assert!(matches!(
+            super::find_esp(&d),
+            Err(super::Error::InvalidHeaderChecksum)
+        ));
+
+        d.as_mut_bytes()[446 + 4] = 0x83;
+        assert!(matches!(
+            super::find_esp(&d),
+            Err(super::Error::NoProtectiveMbr)
+        ));
+    }
+
+    #[test]
+    fn test_find_esp() {
         let d = FakeDisk::new(&clear_disk_path());
 
-        match super::find_efi_partition(&d) {
-            Ok((start, end)) => {
-                assert_eq!(start, 2048);
-                assert_eq!(end, 1_048_575);
+        match super::find_esp(&d) {
+            Ok(esp) => {
+                assert_eq!(esp.first_lba, 2048);
+                assert_eq!(esp.last_lba, 1_048_575);
             }
             Err(e) => panic!("{e:?}"),
         }
@@ -406,7 +406,6 @@ impl PciDevice {
 enum VirtioPciCapabilityType {
     CommonConfig = 1,
     NotifyConfig = 2,
-    #[allow(unused)]
     IsrConfig = 3,
     DeviceConfig = 4,
     #[allow(unused)]
@@ -419,6 +418,7 @@ pub struct VirtioPciTransport {
     region: mem::MemoryRegion,               // common configuration region
     notify_region: mem::MemoryRegion,        // notify region
     notify_off_multiplier: u32,              // from notify config cap
+    isr_region: mem::MemoryRegion,           // ISR status region
     device_config_region: mem::MemoryRegion, // device specific region
 }
 
@@ -429,6 +429,27 @@ impl VirtioPciTransport {
             ..Default::default()
         }
     }
+
+    // Capability structure within its BAR, empty if the BAR is unassigned or
+    // does not hold all of the structure
+    fn capability_region(&self, bar: u8, offset: u32, length: u32) -> mem::MemoryRegion {
+        let end = u64::from(offset) + u64::from(length);
+        self.device
+            .bars
+            .get(usize::from(bar))
+            .and_then(|bar| mem::MemoryRegion::from_bar(bar.address, bar.size as usize))
+            .filter(|bar| bar.length() >= end)
+            .map(|bar| mem::MemoryRegion::new(bar.base() + u64::from(offset), u64::from(length)))
+            .unwrap_or_default()
+    }
+
+    // 64-bit fields in the common configuration are only guaranteed to be
+    // accessible as two 32-bit halves so write them separately, low first
+    fn write_u64_split(&self, offset: u64, value: u64) {
+        self.region.io_write_le_u32(offset, value as u32);
+        self.region
+            .io_write_le_u32(offset + 4, (value >> 32) as u32);
+    }
 }
 // Common Configuration registers:
 /// le32 device_feature_select;     // 0x00 // read-write
@@ -449,8 +470,20 @@ impl VirtioPciTransport {
 /// le64 queue_avail;               // 0x28 // read-write
 /// le64 queue_used;                // 0x30 // read-write
 
+// Size of the common configuration structure laid out above
+const COMMON_CONFIG_SIZE: u32 = 0x38;
+
 impl VirtioTransport for VirtioPciTransport {
-    fn init(&mut self, _device_type: u32) -> Result<(), VirtioError> {
+    fn init(&mut self, device_type: u32) -> Result<(), VirtioError> {
+        // Modern virtio PCI devices use a device ID of 0x1040 + device type
+        if u32::from(self.device.device_id) != 0x1040 + device_type {
+            log!(
+                "Unexpected virtio PCI device ID: {:x}",
+                self.device.device_id
+            );
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
         // Read status register
         let status = self.device.read_u16(0x06);
 
@@ -483,18 +516,16 @@ impl VirtioTransport for VirtioPciTransport {
                 let offset = self.device.read_u32(cap_next + 8);
                 let length = self.device.read_u32(cap_next + 12);
 
-                if cfg_type == VirtioPciCapabilityType::CommonConfig as u8 {
-                    self.region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                // The other structures can be smaller but the common
+                // configuration must hold all of its registers
+                if cfg_type == VirtioPciCapabilityType::CommonConfig as u8
+                    && length >= COMMON_CONFIG_SIZE
+                {
+                    self.region = self.capability_region(bar, offset, length);
                 }
 
                 if cfg_type == VirtioPciCapabilityType::NotifyConfig as u8 {
-                    self.notify_region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                    self.notify_region = self.capability_region(bar, offset, length);
 
                     // struct virtio_pci_notify_cap {
                     //         struct virtio_pci_cap cap;
@@ -503,16 +534,27 @@ impl VirtioTransport for VirtioPciTransport {
                     self.notify_off_multiplier = self.device.read_u32(cap_next + 16);
                 }
 
+                if cfg_type == VirtioPciCapabilityType::IsrConfig as u8 {
+                    self.isr_region = self.capability_region(bar, offset, length);
+                }
+
                 if cfg_type == VirtioPciCapabilityType::DeviceConfig as u8 {
-                    self.device_config_region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                    self.device_config_region = self.capability_region(bar, offset, length);
                 }
             }
             cap_next = self.device.read_u8(cap_next + 1)
         }
 
+        // The common, notify and device configuration structures are all
+        // required for the driver to operate the device
+        if self.region.length() == 0
+            || self.notify_region.length() == 0
+            || self.device_config_region.length() == 0
+        {
+            log!("Missing required virtio PCI capabilities");
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
         Ok(())
     }
 
@@ -536,74 +578,169 @@ impl VirtioTransport for VirtioPciTransport {
 
     fn get_features(&self) -> u64 {
         // device_feature_select: 0x00
-        self.region.io_write_u32(0x00, 0);
+        self.region.io_write_le_u32(0x00, 0);
         // device_feature: 0x04
-        let mut device_features: u64 = u64::from(self.region.io_read_u32(0x04));
+        let mut device_features: u64 = u64::from(self.region.io_read_le_u32(0x04));
         // device_feature_select: 0x00
-        self.region.io_write_u32(0x00, 1);
+        self.region.io_write_le_u32(0x00, 1);
         // device_feature: 0x04
-        device_features |= u64::from(self.region.io_read_u32(0x04)) << 32;
+        device_features |= u64::from(self.region.io_read_le_u32(0x04)) << 32;
 
         device_features
     }
 
     fn set_features(&self, features: u64) {
         // driver_feature_select: 0x08
-        self.region.io_write_u32(0x08, 0);
+        self.region.io_write_le_u32(0x08, 0);
         // driver_feature: 0x0c
-        self.region.io_write_u32(0x0c, features as u32);
+        self.region.io_write_le_u32(0x0c, features as u32);
         // driver_feature_select: 0x08
-        self.region.io_write_u32(0x08, 1);
+        self.region.io_write_le_u32(0x08, 1);
         // driver_feature: 0x0c
-        self.region.io_write_u32(0x0c, (features >> 32) as u32);
+        self.region.io_write_le_u32(0x0c, (features >> 32) as u32);
     }
 
     fn set_queue(&self, queue: u16) {
         // queue_select: 0x16
-        self.region.io_write_u16(0x16, queue);
+        self.region.io_write_le_u16(0x16, queue);
     }
 
     fn get_queue_max_size(&self) -> u16 {
         // queue_size: 0x18
-        self.region.io_read_u16(0x18)
+        self.region.io_read_le_u16(0x18)
     }
 
     fn set_queue_size(&self, queue_size: u16) {
         // queue_size: 0x18
-        self.region.io_write_u16(0x18, queue_size);
+        self.region.io_write_le_u16(0x18, queue_size);
+    }
+
+    fn get_queue_size(&self) -> Option<u16> {
+        // queue_size: 0x18
+        Some(self.region.io_read_le_u16(0x18))
     }
 
     fn set_descriptors_address(&self, addr: u64) {
         // queue_desc: 0x20
-        self.region.io_write_u64(0x20, addr);
+        self.write_u64_split(0x20, addr);
     }
 
     fn set_avail_ring(&self, addr: u64) {
         // queue_avail: 0x28
-        self.region.io_write_u64(0x28, addr);
+        self.write_u64_split(0x28, addr);
     }
 
     fn set_used_ring(&self, addr: u64) {
-        // queue_used: 0x28
-        self.region.io_write_u64(0x30, addr);
+        // queue_used: 0x30
+        self.write_u64_split(0x30, addr);
     }
 
     fn set_queue_enable(&self) {
         // queue_enable: 0x1c
-        self.region.io_write_u16(0x1c, 0x1);
+        self.region.io_write_le_u16(0x1c, 0x1);
     }
 
     fn notify_queue(&self, queue: u16) {
+        // The notify offset is per queue, read it for the one being notified
+        // rather than whichever happens to be selected
+        self.set_queue(queue);
+
         // queue_notify_off: 0x1e
-        let queue_notify_off = self.region.io_read_u16(0x1e);
+        let queue_notify_off = self.region.io_read_le_u16(0x1e);
 
-        self.notify_region.io_write_u32(
+        self.notify_region.io_write_le_u32(
             u64::from(queue_notify_off) * u64::from(self.notify_off_multiplier),
             u32::from(queue),
         );
     }
 
     fn read_device_config(&self, offset: u64) -> u32 {
-        self.device_config_region.io_read_u32(offset)
+        self.device_config_region.io_read_le_u32(offset)
+    }
+
+    fn write_device_config_u8(&self, offset: u64, value: u8) {
+        self.device_config_region.io_write_u8(offset, value)
+    }
+
+    fn get_config_generation(&self) -> u32 {
+        // config_generation: 0x15
+        u32::from(self.region.io_read_u8(0x15))
+    }
+
+    fn ack_interrupt(&self) {
+        // Reading the ISR status clears it and deasserts the interrupt
+        if self.isr_region.length() > 0 {
+            self.isr_region.io_read_u8(0);
+        }
+    }
+
+    // A device that has gone away reads back as all ones
+    fn is_present(&self) -> bool {
+        self.device.read_u16(0x00) != INVALID_VENDOR_ID
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtioPciTransport;
+    use crate::{mem, virtio::VirtioTransport};
+
+    #[test]
+    fn test_capability_region() {
+        let mut transport = VirtioPciTransport::default();
+        transport.device.bars[1].address = 0xfe00_0000;
+        transport.device.bars[1].size = 0x4000;
+
+        let region = transport.capability_region(1, 0x3000, 0x1000);
+        assert_eq!(region.base(), 0xfe00_3000);
+        assert_eq!(region.length(), 0x1000);
+
+        // Past the end of the BAR, in an unassigned BAR or no BAR at all
+        assert_eq!(transport.capability_region(1, 0x3000, 0x1001).length(), 0);
+        assert_eq!(transport.capability_region(0, 0, 0x38).length(), 0);
+        assert_eq!(transport.capability_region(6, 0, 0x38).length(), 0);
+
+        // BARs smaller than the common configuration can hold e.g. the ISR
+        transport.device.bars[2].address = 0xfe01_0000;
+        transport.device.bars[2].size = 0x4;
+        assert_eq!(transport.capability_region(2, 0, 1).length(), 1);
+    }
+
+    #[test]
+    fn test_queue_address_split() {
+        let mut common_config = [0u8; 0x38];
+        let transport = VirtioPciTransport {
+            region: mem::MemoryRegion::from_bytes(&mut common_config),
+            ..Default::default()
+        };
+
+        transport.set_descriptors_address(0x1234_5678_9abc_def0);
+        transport.set_avail_ring(0xffff_ffff_0000_0002);
+        transport.set_used_ring(0x0000_0001_0000_0004);
+
+        let read_u64 = |offset: usize| {
+            u64::from_le_bytes(common_config[offset..offset + 8].try_into().unwrap())
+        };
+        assert_eq!(read_u64(0x20), 0x1234_5678_9abc_def0);
+        assert_eq!(read_u64(0x28), 0xffff_ffff_0000_0002);
+        assert_eq!(read_u64(0x30), 0x0000_0001_0000_0004);
+    }
+
+    #[test]
+    fn test_notify_queue() {
+        let mut common_config = [0u8; 0x38];
+        common_config[0x1e] = 2;
+        let mut notify = [0xffu8; 16];
+        let transport = VirtioPciTransport {
+            region: mem::MemoryRegion::from_bytes(&mut common_config),
+            notify_region: mem::MemoryRegion::from_bytes(&mut notify),
+            notify_off_multiplier: 4,
+            ..Default::default()
+        };
+
+        transport.notify_queue(1);
+        assert_eq!(common_config[0x16], 1);
+        assert_eq!(notify[8..12], [1, 0, 0, 0]);
+        assert_eq!(notify[..8], [0xff; 8]);
     }
 }
@@ -282,9 +282,9 @@ mod tests {
     #[test]
     fn test_loader() {
         let d = FakeDisk::new(&clear_disk_path());
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
+        let esp = crate::part::find_esp(&d).unwrap();
 
-        let mut f = crate::fat::Filesystem::new(&d, start, end);
+        let mut f = crate::fat::Filesystem::new(&d, esp.first_lba, esp.last_lba);
         f.init().unwrap();
         let mut file = f.open("/EFI/BOOT/BOOTX64 EFI").unwrap();
         let mut l = super::Loader::new(&mut file);
@@ -0,0 +1,85 @@
+// Copyright © 2019 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Helpers shared by the tests of several modules
+
+use std::cell::{Cell, RefCell};
+
+#[cfg(feature = "block-write")]
+use crate::block::SectorWrite;
+use crate::block::{Error, SectorBuf, SectorRead};
+
+/// In-memory disk for testing code built on top of the block traits.
+/// Counts the number of sectors read.
+pub struct MemBlockDevice {
+    sectors: RefCell<Vec<u8>>,
+    pub reads: Cell<usize>,
+}
+
+impl MemBlockDevice {
+    /// Disk of `count` sectors where the contents of each sector is its
+    /// number
+    pub fn new(count: u8) -> MemBlockDevice {
+        Self::from_bytes((0..count).flat_map(|i| [i; 512]).collect())
+    }
+
+    /// Disk holding `data`, the length of which must be a multiple of 512
+    pub fn from_bytes(data: Vec<u8>) -> MemBlockDevice {
+        assert_eq!(data.len() % SectorBuf::len(), 0);
+        MemBlockDevice {
+            sectors: RefCell::new(data),
+            reads: Cell::new(0),
+        }
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        self.sectors.get_mut()
+    }
+
+    fn range(&self, sector: u64, len: usize) -> Result<core::ops::Range<usize>, Error> {
+        if len != SectorBuf::len() {
+            return Err(Error::InvalidDataBufSize);
+        }
+        let start = usize::try_from(sector)
+            .ok()
+            .and_then(|s| s.checked_mul(SectorBuf::len()))
+            .ok_or(Error::SectorOutOfRange)?;
+        if start + len > self.sectors.borrow().len() {
+            return Err(Error::SectorOutOfRange);
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl SectorRead for MemBlockDevice {
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        let range = self.range(sector, data.len())?;
+        self.reads.set(self.reads.get() + 1);
+        data.copy_from_slice(&self.sectors.borrow()[range]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "block-write")]
+impl SectorWrite for MemBlockDevice {
+    fn write(&self, sector: u64, data: &[u8]) -> Result<(), Error> {
+        let range = self.range(sector, data.len())?;
+        self.sectors.borrow_mut()[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -12,6 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// Feature bit for devices that accept descriptors pointing at a table of
+/// further descriptors
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+/// Feature bit offered by all non-legacy devices
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Device status bits
+pub const VIRTIO_STATUS_RESET: u32 = 0;
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+pub const VIRTIO_STATUS_FAILED: u32 = 0x80;
+
+/// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+// Number of times the device status is read back waiting for FEATURES_OK to
+// latch before giving up on feature negotiation
+const FEATURES_OK_RETRIES: usize = 16;
+
+// Number of attempts at reading a 64-bit config field while the device keeps
+// changing the config generation
+const CONFIG_GENERATION_RETRIES: usize = 16;
+
 /// Virtio related errors
 #[derive(Debug)]
 pub enum Error {
@@ -19,6 +47,36 @@ pub enum Error {
     LegacyOnly,
     FeatureNegotiationFailed,
     QueueTooSmall,
+    MisalignedQueue,
+    QueueFull,
+    MisalignedBase,
+    /// The device reports a maximum size of zero for the queue, it does not
+    /// exist or is already in use
+    QueueUnavailable,
+    /// The config generation kept changing while reading a config field
+    ConfigUnstable,
+}
+
+/// Type of a virtio device given by its device ID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Net,
+    Block,
+    Console,
+    Rng,
+    Other(u32),
+}
+
+impl From<u32> for DeviceKind {
+    fn from(device_id: u32) -> DeviceKind {
+        match device_id {
+            1 => DeviceKind::Net,
+            2 => DeviceKind::Block,
+            3 => DeviceKind::Console,
+            4 => DeviceKind::Rng,
+            id => DeviceKind::Other(id),
+        }
+    }
 }
 
 /// Trait to allow separation of transport from block driver
@@ -33,10 +91,476 @@ pub trait VirtioTransport {
     fn set_queue(&self, queue: u16);
     fn get_queue_max_size(&self) -> u16;
     fn set_queue_size(&self, queue_size: u16);
+    /// Size of the selected queue as currently programmed, `None` if the
+    /// transport cannot read it back
+    fn get_queue_size(&self) -> Option<u16> {
+        None
+    }
     fn set_descriptors_address(&self, address: u64);
     fn set_avail_ring(&self, address: u64);
     fn set_used_ring(&self, address: u64);
     fn set_queue_enable(&self);
     fn notify_queue(&self, queue: u16);
     fn read_device_config(&self, offset: u64) -> u32;
+    fn write_device_config_u8(&self, offset: u64, value: u8);
+    /// Changes whenever the device configuration space is updated, used to
+    /// detect torn reads of fields spanning more than one register.
+    fn get_config_generation(&self) -> u32;
+    /// Acknowledge any pending interrupt so the device deasserts it
+    fn ack_interrupt(&self);
+    /// Whether the device still responds at all, e.g. its registers are
+    /// still mapped. Transports that cannot tell assume it does.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Reset the device and carry out the feature negotiation common to all
+    /// device types, returning the subset of `supported_features` accepted
+    fn init_common(&mut self, device_type: u32, supported_features: u64) -> Result<u64, Error> {
+        // Initialise the transport
+        self.init(device_type)?;
+
+        // Reset device
+        self.set_status(VIRTIO_STATUS_RESET);
+
+        // Acknowledge
+        self.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+
+        // And advertise driver
+        self.add_status(VIRTIO_STATUS_DRIVER);
+
+        // Request device features
+        let device_features = self.get_features();
+
+        if device_features & VIRTIO_F_VERSION_1 != VIRTIO_F_VERSION_1 {
+            self.add_status(VIRTIO_STATUS_FAILED);
+            return Err(Error::LegacyOnly);
+        }
+
+        // Report driver features
+        let features = device_features & supported_features;
+        self.set_features(features);
+
+        // Some devices take a moment to latch the status so allow a few
+        // readbacks before deciding the features were refused
+        self.add_status(VIRTIO_STATUS_FEATURES_OK);
+        let latched = (0..FEATURES_OK_RETRIES).any(|_| {
+            if self.get_status() & VIRTIO_STATUS_FEATURES_OK == VIRTIO_STATUS_FEATURES_OK {
+                return true;
+            }
+            core::hint::spin_loop();
+            false
+        });
+        if !latched {
+            self.add_status(VIRTIO_STATUS_FAILED);
+            return Err(Error::FeatureNegotiationFailed);
+        }
+
+        Ok(features)
+    }
+
+    /// Program and enable `queue` using as many of the driver's `max_size`
+    /// entries as the device supports, rounded down to a power of two.
+    /// Returns the queue size which is at least `min_size`.
+    fn setup_queue(
+        &self,
+        queue: u16,
+        max_size: usize,
+        min_size: usize,
+        descriptors: u64,
+        avail: u64,
+        used: u64,
+    ) -> Result<usize, Error> {
+        // Alignment required of each part of a split virtqueue
+        if descriptors % 16 != 0 || avail % 2 != 0 || used % 4 != 0 {
+            self.add_status(VIRTIO_STATUS_FAILED);
+            return Err(Error::MisalignedQueue);
+        }
+
+        self.set_queue(queue);
+
+        let max_queue = self.get_queue_max_size();
+        if max_queue == 0 {
+            self.add_status(VIRTIO_STATUS_FAILED);
+            return Err(Error::QueueUnavailable);
+        }
+        let queue_size = core::cmp::min(max_size, usize::from(max_queue));
+        let queue_size = queue_size.checked_ilog2().map_or(0, |bits| 1 << bits);
+        if queue_size < min_size {
+            self.add_status(VIRTIO_STATUS_FAILED);
+            return Err(Error::QueueTooSmall);
+        }
+        self.set_queue_size(queue_size as u16);
+
+        // A device that silently clamps the size would have a different idea
+        // of where the rings wrap
+        if let Some(programmed) = self.get_queue_size() {
+            if usize::from(programmed) != queue_size {
+                self.add_status(VIRTIO_STATUS_FAILED);
+                return Err(Error::QueueTooSmall);
+            }
+        }
+
+        self.set_descriptors_address(descriptors);
+        self.set_avail_ring(avail);
+        self.set_used_ring(used);
+
+        // Confirm queue
+        self.set_queue_enable();
+
+        Ok(queue_size)
+    }
+
+    /// Report that the driver is ready to drive the device
+    fn driver_ok(&self) {
+        self.add_status(VIRTIO_STATUS_DRIVER_OK);
+    }
+
+    /// Read a 64-bit config field, retrying a few times if the device updated
+    /// the config space between reading the two halves
+    fn read_device_config_u64(&self, offset: u64) -> Result<u64, Error> {
+        for _ in 0..CONFIG_GENERATION_RETRIES {
+            let generation = self.get_config_generation();
+            let value = u64::from(self.read_device_config(offset))
+                | u64::from(self.read_device_config(offset + 4)) << 32;
+            if self.get_config_generation() == generation {
+                return Ok(value);
+            }
+        }
+        Err(Error::ConfigUnstable)
+    }
+}
+
+#[repr(C)]
+#[repr(align(16))]
+#[derive(Clone, Copy, Default)]
+/// A virtio queue entry descriptor
+pub struct Desc {
+    pub addr: u64,
+    pub length: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+#[repr(align(2))]
+/// The virtio available ring
+pub struct AvailRing<const N: usize> {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [u16; N],
+}
+
+#[repr(C)]
+#[repr(align(4))]
+/// The virtio used ring
+pub struct UsedRing<const N: usize> {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [UsedElem; N],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A single element in the used ring
+pub struct UsedElem {
+    pub id: u32,
+    pub len: u32,
+}
+
+// The device accesses these directly so their layout is fixed by the spec
+const _: () = assert!(core::mem::size_of::<Desc>() == 16);
+const _: () = assert!(core::mem::size_of::<UsedElem>() == 8);
+const _: () = assert!(core::mem::size_of::<AvailRing<16>>() == 4 + 2 * 16);
+const _: () = assert!(core::mem::size_of::<UsedRing<16>>() == 4 + 8 * 16);
+
+/// Buffer making up part of a descriptor chain
+#[derive(Clone, Copy, Default)]
+pub struct DescSpec {
+    pub addr: u64,
+    pub length: u32,
+    /// `VIRTQ_DESC_F_WRITE` or `VIRTQ_DESC_F_INDIRECT`, chains are linked
+    /// by the queue
+    pub flags: u16,
+}
+
+/// Fill `table` with `chain` linked in order, for use as an indirect table
+pub fn fill_indirect_table(table: &mut [Desc], chain: &[DescSpec]) {
+    for (i, (d, spec)) in table.iter_mut().zip(chain).enumerate() {
+        let last = i + 1 == chain.len();
+        *d = Desc {
+            addr: spec.addr,
+            length: spec.length,
+            flags: if last {
+                spec.flags
+            } else {
+                spec.flags | VIRTQ_DESC_F_NEXT
+            },
+            next: if last { 0 } else { (i + 1) as u16 },
+        };
+    }
+}
+
+/// Split virtqueue of up to `N` entries owning its descriptor table and
+/// rings. It only tracks descriptors so can be used for any device type over
+/// any transport, `N` must be a power of two.
+#[repr(C)]
+pub struct Virtqueue<const N: usize> {
+    descriptors: [Desc; N],
+    avail: AvailRing<N>,
+    used: UsedRing<N>,
+    last_used_idx: u16,
+    size: usize,
+    // Unused descriptors are kept on a list linked through their next field
+    free_head: u16,
+    num_free: usize,
+}
+
+impl<const N: usize> Default for Virtqueue<N> {
+    fn default() -> Self {
+        Virtqueue {
+            descriptors: [Desc::default(); N],
+            avail: AvailRing {
+                flags: 0,
+                idx: 0,
+                ring: [0; N],
+            },
+            used: UsedRing {
+                flags: 0,
+                idx: 0,
+                ring: [UsedElem::default(); N],
+            },
+            last_used_idx: 0,
+            size: 0,
+            free_head: 0,
+            num_free: 0,
+        }
+    }
+}
+
+impl<const N: usize> Virtqueue<N> {
+    /// Program `queue` of the device to use these rings with at most
+    /// `max_size` entries, see `VirtioTransport::setup_queue()`. Returns the
+    /// queue size, all descriptors start out free.
+    pub fn setup<T: VirtioTransport + ?Sized>(
+        &mut self,
+        transport: &T,
+        queue: u16,
+        max_size: usize,
+        min_size: usize,
+    ) -> Result<usize, Error> {
+        self.size = transport.setup_queue(
+            queue,
+            core::cmp::min(N, max_size),
+            min_size,
+            self.descriptors.as_ptr() as u64,
+            (&self.avail as *const _) as u64,
+            (&self.used as *const _) as u64,
+        )?;
+        self.reset_free_list();
+        Ok(self.size)
+    }
+
+    /// Number of entries in use, zero until set up
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of descriptors not part of a chain
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    /// Number of chains made available that have yet to be reclaimed
+    pub fn num_outstanding(&self) -> u16 {
+        self.avail.idx.wrapping_sub(self.last_used_idx)
+    }
+
+    /// Index of the next entry the device will write in the used ring
+    pub fn used_idx(&self) -> u16 {
+        unsafe { core::ptr::read_volatile(&self.used.idx) }
+    }
+
+    pub fn descriptor(&self, index: u16) -> &Desc {
+        &self.descriptors[usize::from(index)]
+    }
+
+    /// Head of the chain that the next `push_chain()` returns, for setting up
+    /// storage indexed by it that the chain points into
+    pub fn next_head(&self) -> u16 {
+        self.free_head
+    }
+
+    /// Forget about all chains and link every descriptor into the free list
+    pub fn reset_free_list(&mut self) {
+        for (i, d) in self.descriptors[..self.size].iter_mut().enumerate() {
+            d.next = (i + 1) as u16;
+        }
+        self.free_head = 0;
+        self.num_free = self.size;
+    }
+
+    // The ring indices are free running and wrap at 2^16, entries are found
+    // modulo the queue size which may be smaller than the ring arrays. As the
+    // queue size is a power of two the entries stay in step across the wrap.
+    fn avail_slot(&mut self, idx: u16) -> &mut u16 {
+        &mut self.avail.ring[usize::from(idx) % self.size]
+    }
+
+    fn used_slot(&self, idx: u16) -> &UsedElem {
+        &self.used.ring[usize::from(idx) % self.size]
+    }
+
+    /// Fill descriptors from the free list with `chain` and make it available
+    /// to the device, returning the head of the chain. The device must then
+    /// be notified through the transport.
+    pub fn push_chain(&mut self, chain: &[DescSpec]) -> Result<u16, Error> {
+        if chain.is_empty() || chain.len() > self.num_free {
+            return Err(Error::QueueFull);
+        }
+
+        // The last descriptor links to the rest of the free list, strict
+        // devices expect next to be clear without VIRTQ_DESC_F_NEXT
+        let head = self.free_head;
+        let mut index = usize::from(head);
+        for (i, spec) in chain.iter().enumerate() {
+            let d = &mut self.descriptors[index];
+            d.addr = spec.addr;
+            d.length = spec.length;
+            if i + 1 < chain.len() {
+                d.flags = spec.flags | VIRTQ_DESC_F_NEXT;
+                index = usize::from(d.next);
+            } else {
+                d.flags = spec.flags;
+                self.free_head = d.next;
+                d.next = 0;
+            }
+        }
+        self.num_free -= chain.len();
+
+        // Update ring to point to head of chain. Fence. Then update idx
+        let avail_index = self.avail.idx;
+        *self.avail_slot(avail_index) = head;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+
+        unsafe {
+            core::ptr::write_volatile(&mut self.avail.idx, avail_index.wrapping_add(1));
+        }
+
+        // The descriptor and ring updates must be visible to the device before
+        // it is notified
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+
+        Ok(head)
+    }
+
+    /// Take the next chain returned by the device from the used ring, giving
+    /// its head and the number of bytes written into it. The descriptors stay
+    /// allocated until `free_chain()` so storage indexed by the head remains
+    /// valid.
+    pub fn reclaim(&mut self) -> Option<(u16, u32)> {
+        if self.used_idx() == self.last_used_idx {
+            return None;
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        let elem = self.used_slot(self.last_used_idx);
+        let head = unsafe { core::ptr::read_volatile(&elem.id) } as u16;
+        let len = unsafe { core::ptr::read_volatile(&elem.len) };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        Some((head, len))
+    }
+
+    /// Return a chain that the device has finished with to the free list
+    pub fn free_chain(&mut self, head: u16) {
+        let mut tail = usize::from(head);
+        let mut count = 1;
+        while self.descriptors[tail].flags & VIRTQ_DESC_F_NEXT != 0 {
+            tail = usize::from(self.descriptors[tail].next);
+            count += 1;
+        }
+        self.descriptors[tail].next = self.free_head;
+        self.free_head = head;
+        self.num_free += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DescSpec, Error, UsedElem, Virtqueue, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
+    };
+
+    fn queue<const N: usize>(size: usize) -> Virtqueue<N> {
+        let mut q = Virtqueue {
+            size,
+            ..Default::default()
+        };
+        q.reset_free_list();
+        q
+    }
+
+    fn spec(addr: u64, flags: u16) -> DescSpec {
+        DescSpec {
+            addr,
+            length: 16,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_ring_slots() {
+        let mut q = queue::<16>(8);
+        *q.avail_slot(9) = 3;
+        *q.avail_slot(u16::MAX) = 4;
+        assert_eq!(q.avail.ring[..8], [0, 3, 0, 0, 0, 0, 0, 4]);
+
+        q.used.ring[7].id = 5;
+        assert_eq!(q.used_slot(u16::MAX).id, 5);
+        assert_eq!(q.used_slot(u16::MAX.wrapping_add(8)).id, 5);
+    }
+
+    #[test]
+    fn test_push_and_reclaim() {
+        let mut q = queue::<8>(4);
+        let a = q.push_chain(&[spec(1, 0), spec(2, VIRTQ_DESC_F_WRITE)]);
+        let b = q.push_chain(&[spec(3, 0), spec(4, 0)]);
+        assert_eq!((a.unwrap(), b.unwrap()), (0, 2));
+        assert!(matches!(q.push_chain(&[spec(5, 0)]), Err(Error::QueueFull)));
+        assert!(matches!(q.push_chain(&[]), Err(Error::QueueFull)));
+
+        assert_eq!(q.descriptor(0).flags, VIRTQ_DESC_F_NEXT);
+        assert_eq!(q.descriptor(0).next, 1);
+        assert_eq!(q.descriptor(1).flags, VIRTQ_DESC_F_WRITE);
+        assert_eq!(q.descriptor(1).next, 0);
+        assert_eq!(q.avail.idx, 2);
+        assert_eq!(q.avail.ring[..2], [0, 2]);
+
+        // The device returns the second chain first
+        assert_eq!(q.reclaim(), None);
+        q.used.ring[0] = UsedElem { id: 2, len: 7 };
+        q.used.idx = 1;
+        assert_eq!(q.reclaim(), Some((2, 7)));
+        assert_eq!(q.reclaim(), None);
+        assert_eq!(q.num_outstanding(), 1);
+        assert_eq!(q.num_free(), 0);
+
+        q.free_chain(2);
+        assert_eq!(q.num_free(), 2);
+        assert_eq!(q.next_head(), 2);
+        assert_eq!(q.push_chain(&[spec(6, VIRTQ_DESC_F_INDIRECT)]).unwrap(), 2);
+        assert_eq!(q.descriptor(2).flags, VIRTQ_DESC_F_INDIRECT);
+        assert_eq!(q.next_head(), 3);
+    }
+
+    #[test]
+    fn test_indirect_table() {
+        let mut table = [super::Desc::default(); 4];
+        super::fill_indirect_table(&mut table, &[spec(1, 0), spec(2, VIRTQ_DESC_F_WRITE)]);
+        assert_eq!(table[0].flags, VIRTQ_DESC_F_NEXT);
+        assert_eq!(table[0].next, 1);
+        assert_eq!(table[1].addr, 2);
+        assert_eq!(table[1].flags, VIRTQ_DESC_F_WRITE);
+        assert_eq!(table[1].next, 0);
+    }
 }
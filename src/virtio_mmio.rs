@@ -0,0 +1,510 @@
+// Copyright © 2019 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    mem::MemoryRegion,
+    virtio::{DeviceKind, Error as VirtioError, VirtioTransport, VIRTIO_F_VERSION_1},
+};
+
+/// Register layout of a modern (version 2) virtio-mmio device, all registers
+/// are 32 bits wide. The device specific configuration follows at 0x100.
+#[repr(C)]
+struct Registers {
+    magic_value: u32,
+    version: u32,
+    device_id: u32,
+    vendor_id: u32,
+    device_features: u32,
+    device_features_sel: u32,
+    _reserved0: [u32; 2],
+    driver_features: u32,
+    driver_features_sel: u32,
+    _reserved1: [u32; 2],
+    queue_sel: u32,
+    queue_num_max: u32,
+    queue_num: u32,
+    _reserved2: [u32; 2],
+    queue_ready: u32,
+    _reserved3: [u32; 2],
+    queue_notify: u32,
+    _reserved4: [u32; 3],
+    interrupt_status: u32,
+    interrupt_ack: u32,
+    _reserved5: [u32; 2],
+    status: u32,
+    _reserved6: [u32; 3],
+    queue_desc_low: u32,
+    queue_desc_high: u32,
+    _reserved7: [u32; 2],
+    queue_driver_low: u32,
+    queue_driver_high: u32,
+    _reserved8: [u32; 2],
+    queue_device_low: u32,
+    queue_device_high: u32,
+    _reserved9: [u32; 21],
+    config_generation: u32,
+}
+
+const CONFIG: u64 = core::mem::size_of::<Registers>() as u64;
+
+// Offset of a register within the window
+macro_rules! reg {
+    ($field:ident) => {
+        offset_of!(Registers, $field) as u64
+    };
+}
+
+/// Size of the register window including the start of the device config
+pub const REGION_SIZE: u64 = 0x200;
+
+/// Size of the page a virtio-mmio device is mapped into, its base must be
+/// aligned to this
+pub const VIRTIO_MMIO_WINDOW: u64 = 0x1000;
+
+// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+#[derive(Default)]
+pub struct VirtioMmioTransport {
+    region: MemoryRegion,
+}
+
+// Nothing discovers virtio-mmio devices yet as the boot info does not say
+// where they are, so for now only the tests create this transport
+#[allow(dead_code)]
+impl VirtioMmioTransport {
+    pub fn new(region: MemoryRegion) -> VirtioMmioTransport {
+        VirtioMmioTransport { region }
+    }
+
+    /// Map the window of a device at `base`, e.g. one given on the command
+    /// line, refusing a base that is not page aligned
+    pub fn from_base(base: u64) -> Result<VirtioMmioTransport, VirtioError> {
+        if base % VIRTIO_MMIO_WINDOW != 0 {
+            return Err(VirtioError::MisalignedBase);
+        }
+        Ok(VirtioMmioTransport::new(MemoryRegion::new(
+            base,
+            VIRTIO_MMIO_WINDOW,
+        )))
+    }
+
+    pub fn read_magic(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(magic_value))
+    }
+
+    pub fn read_version(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(version))
+    }
+
+    /// Virtio device type, zero if there is no device behind this window
+    pub fn read_device_id(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(device_id))
+    }
+
+    pub fn read_vendor_id(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(vendor_id))
+    }
+
+    pub fn read_config_generation(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(config_generation))
+    }
+
+    // 64-bit queue addresses are split over a pair of registers
+    fn write_u64_split(&self, low: u64, high: u64, value: u64) {
+        self.region.io_write_le_u32(low, value as u32);
+        self.region.io_write_le_u32(high, (value >> 32) as u32);
+    }
+
+    // Check that this is a modern virtio-mmio device
+    fn check_header(&self) -> Result<(), VirtioError> {
+        if self.read_magic() != MAGIC {
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
+        // Version 1 is the legacy interface
+        match self.read_version() {
+            1 => return Err(VirtioError::LegacyOnly),
+            2 => {}
+            _ => return Err(VirtioError::UnsupportedDevice),
+        }
+
+        Ok(())
+    }
+
+    // Check that this is a modern device of the right type
+    fn identify(&self, device_type: u32) -> Result<(), VirtioError> {
+        self.check_header()?;
+        if self.read_device_id() != device_type {
+            return Err(VirtioError::UnsupportedDevice);
+        }
+
+        Ok(())
+    }
+
+    /// Type of the modern device behind this window, fails if there is none.
+    /// Like `probe()` this leaves the device untouched.
+    pub fn device_kind(&self) -> Result<DeviceKind, VirtioError> {
+        self.check_header()?;
+        match self.read_device_id() {
+            0 => Err(VirtioError::UnsupportedDevice),
+            id => Ok(DeviceKind::from(id)),
+        }
+    }
+
+    /// Check whether a modern device of `device_type` is behind this window
+    /// without changing the state of the device. Only the feature select
+    /// register is written to read the offered features.
+    pub fn probe(&self, device_type: u32) -> Result<(), VirtioError> {
+        self.identify(device_type)?;
+        if self.get_features() & VIRTIO_F_VERSION_1 == 0 {
+            return Err(VirtioError::LegacyOnly);
+        }
+        Ok(())
+    }
+}
+
+/// Probe `count` register windows laid out every `stride` bytes from `base`,
+/// yielding a transport for each one holding a modern device of
+/// `device_type`. Devices are not disturbed by the scan.
+#[allow(dead_code)]
+pub fn scan(
+    base: u64,
+    stride: u64,
+    count: usize,
+    device_type: u32,
+) -> impl Iterator<Item = VirtioMmioTransport> {
+    (0..count as u64)
+        .map(move |i| VirtioMmioTransport::new(MemoryRegion::new(base + i * stride, REGION_SIZE)))
+        .filter(move |t| t.probe(device_type).is_ok())
+}
+
+impl VirtioTransport for VirtioMmioTransport {
+    fn init(&mut self, device_type: u32) -> Result<(), VirtioError> {
+        self.identify(device_type)
+    }
+
+    fn get_status(&self) -> u32 {
+        self.region.io_read_le_u32(reg!(status))
+    }
+
+    fn set_status(&self, value: u32) {
+        self.region.io_write_le_u32(reg!(status), value);
+    }
+
+    fn add_status(&self, value: u32) {
+        self.set_status(self.get_status() | value);
+    }
+
+    fn reset(&self) {
+        self.set_status(0);
+    }
+
+    fn get_features(&self) -> u64 {
+        self.region.io_write_le_u32(reg!(device_features_sel), 0);
+        let mut device_features = u64::from(self.region.io_read_le_u32(reg!(device_features)));
+        self.region.io_write_le_u32(reg!(device_features_sel), 1);
+        device_features |= u64::from(self.region.io_read_le_u32(reg!(device_features))) << 32;
+
+        device_features
+    }
+
+    fn set_features(&self, features: u64) {
+        self.region.io_write_le_u32(reg!(driver_features_sel), 0);
+        self.region
+            .io_write_le_u32(reg!(driver_features), features as u32);
+        self.region.io_write_le_u32(reg!(driver_features_sel), 1);
+        self.region
+            .io_write_le_u32(reg!(driver_features), (features >> 32) as u32);
+    }
+
+    fn set_queue(&self, queue: u16) {
+        self.region
+            .io_write_le_u32(reg!(queue_sel), u32::from(queue));
+    }
+
+    fn get_queue_max_size(&self) -> u16 {
+        self.region.io_read_le_u32(reg!(queue_num_max)) as u16
+    }
+
+    fn set_queue_size(&self, queue_size: u16) {
+        self.region
+            .io_write_le_u32(reg!(queue_num), u32::from(queue_size));
+    }
+
+    fn set_descriptors_address(&self, addr: u64) {
+        self.write_u64_split(reg!(queue_desc_low), reg!(queue_desc_high), addr);
+    }
+
+    fn set_avail_ring(&self, addr: u64) {
+        self.write_u64_split(reg!(queue_driver_low), reg!(queue_driver_high), addr);
+    }
+
+    fn set_used_ring(&self, addr: u64) {
+        self.write_u64_split(reg!(queue_device_low), reg!(queue_device_high), addr);
+    }
+
+    fn set_queue_enable(&self) {
+        self.region.io_write_le_u32(reg!(queue_ready), 1);
+    }
+
+    fn notify_queue(&self, queue: u16) {
+        self.region
+            .io_write_le_u32(reg!(queue_notify), u32::from(queue));
+    }
+
+    fn read_device_config(&self, offset: u64) -> u32 {
+        self.region.io_read_le_u32(CONFIG + offset)
+    }
+
+    fn write_device_config_u8(&self, offset: u64, value: u8) {
+        self.region.io_write_u8(CONFIG + offset, value)
+    }
+
+    fn get_config_generation(&self) -> u32 {
+        self.read_config_generation()
+    }
+
+    fn ack_interrupt(&self) {
+        let status = self.region.io_read_le_u32(reg!(interrupt_status));
+        if status != 0 {
+            self.region.io_write_le_u32(reg!(interrupt_ack), status);
+        }
+    }
+
+    fn is_present(&self) -> bool {
+        self.read_magic() == MAGIC
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtioMmioTransport;
+    use crate::{
+        mem::MemoryRegion,
+        virtio::{Error as VirtioError, VirtioTransport},
+    };
+
+    fn registers(version: u32, device_id: u32) -> [u8; super::REGION_SIZE as usize] {
+        let mut regs = [0u8; super::REGION_SIZE as usize];
+        regs[0..4].copy_from_slice(&super::MAGIC.to_le_bytes());
+        regs[4..8].copy_from_slice(&version.to_le_bytes());
+        regs[8..12].copy_from_slice(&device_id.to_le_bytes());
+        regs
+    }
+
+    #[test]
+    fn test_init() {
+        let mut regs = registers(2, 2);
+        let mut t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(t.init(2).is_ok());
+        assert!(matches!(t.init(1), Err(VirtioError::UnsupportedDevice)));
+
+        let mut regs = registers(1, 2);
+        let mut t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(matches!(t.init(2), Err(VirtioError::LegacyOnly)));
+
+        let mut regs = [0u8; super::REGION_SIZE as usize];
+        let mut t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(matches!(t.init(2), Err(VirtioError::UnsupportedDevice)));
+    }
+
+    #[test]
+    fn test_probe() {
+        // The fake registers return the last written feature word for either
+        // selector value, so offer VERSION_1 (bit 0 of the high word) in both
+        let mut regs = registers(2, 2);
+        regs[0x10] = 1;
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(t.probe(2).is_ok());
+        assert!(matches!(t.probe(1), Err(VirtioError::UnsupportedDevice)));
+        assert_eq!(regs[0x70], 0);
+
+        let mut regs = registers(2, 2);
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(matches!(t.probe(2), Err(VirtioError::LegacyOnly)));
+    }
+
+    #[test]
+    fn test_scan() {
+        const SIZE: usize = super::REGION_SIZE as usize;
+        let mut windows = [0u8; 4 * SIZE];
+        for (i, (version, device_id)) in [(2, 2), (0, 0), (2, 1), (2, 2)].iter().enumerate() {
+            let w = &mut windows[i * SIZE..(i + 1) * SIZE];
+            w.copy_from_slice(&registers(*version, *device_id));
+            w[0x10] = 1;
+        }
+
+        let base = windows.as_ptr() as u64;
+        let found: Vec<_> = super::scan(base, SIZE as u64, 4, 2)
+            .map(|t| t.region.base())
+            .collect();
+        assert_eq!(found, [base, base + 3 * SIZE as u64]);
+    }
+
+    #[test]
+    fn test_device_kind() {
+        use crate::virtio::DeviceKind;
+
+        for (device_id, kind) in [
+            (1, DeviceKind::Net),
+            (2, DeviceKind::Block),
+            (3, DeviceKind::Console),
+            (4, DeviceKind::Rng),
+            (26, DeviceKind::Other(26)),
+        ] {
+            let mut regs = registers(2, device_id);
+            let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+            assert_eq!(t.device_kind().unwrap(), kind);
+        }
+
+        let mut regs = registers(2, 0);
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(matches!(
+            t.device_kind(),
+            Err(VirtioError::UnsupportedDevice)
+        ));
+
+        let mut regs = registers(1, 2);
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(matches!(t.device_kind(), Err(VirtioError::LegacyOnly)));
+    }
+
+    #[test]
+    fn test_is_present() {
+        let mut regs = registers(2, 2);
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(t.is_present());
+
+        let mut regs = [0xffu8; super::REGION_SIZE as usize];
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        assert!(!t.is_present());
+    }
+
+    #[test]
+    fn test_from_base() {
+        let t = VirtioMmioTransport::from_base(0xd000_0000).unwrap();
+        assert_eq!(t.region.base(), 0xd000_0000);
+        assert_eq!(t.region.length(), super::VIRTIO_MMIO_WINDOW);
+
+        assert!(matches!(
+            VirtioMmioTransport::from_base(0xd000_0200),
+            Err(VirtioError::MisalignedBase)
+        ));
+    }
+
+    // Serve `requests` reads from `disk` in place of a real device, taking
+    // them from queue 0 of the register window at `regs` once it is set up
+    fn serve(regs: usize, disk: Vec<u8>, requests: u16) -> std::thread::JoinHandle<()> {
+        use crate::virtio::Desc;
+        use core::ptr::{read_volatile, write_volatile};
+        use core::sync::atomic::{fence, Ordering};
+
+        std::thread::spawn(move || unsafe {
+            let reg = |offset| read_volatile((regs + offset) as *const u32);
+            let addr = |low| (u64::from(reg(low)) | u64::from(reg(low + 4)) << 32) as usize;
+            let size = reg(0x38) as u16;
+            let (descriptors, avail, used) = (addr(0x80) as *const Desc, addr(0x90), addr(0xa0));
+
+            let mut next = 0u16;
+            while next != requests {
+                if read_volatile((avail + 2) as *const u16) == next {
+                    core::hint::spin_loop();
+                    continue;
+                }
+                fence(Ordering::Acquire);
+
+                // Header, data and footer each have their own descriptor
+                let slot = usize::from(next % size);
+                let head = read_volatile((avail + 4 + 2 * slot) as *const u16);
+                let header = &*descriptors.add(usize::from(head));
+                let data = &*descriptors.add(usize::from(header.next));
+                let footer = &*descriptors.add(usize::from(data.next));
+                assert_eq!(*(header.addr as *const u32), 0);
+
+                let start = *((header.addr + 8) as *const u64) as usize * 512;
+                let buf =
+                    core::slice::from_raw_parts_mut(data.addr as *mut u8, data.length as usize);
+                buf.copy_from_slice(&disk[start..start + buf.len()]);
+                write_volatile(footer.addr as *mut u8, 0);
+
+                let elem = (used + 4 + 8 * slot) as *mut u32;
+                write_volatile(elem, u32::from(head));
+                write_volatile(elem.add(1), data.length + 1);
+                fence(Ordering::Release);
+                next += 1;
+                write_volatile((used + 2) as *mut u16, next);
+            }
+        })
+    }
+
+    #[test]
+    fn test_block_device() {
+        use crate::block::{SectorBuf, SectorRead, VirtioBlockDevice};
+
+        let mut regs = registers(2, 2);
+        // VERSION_1, read back for either half of the features
+        regs[0x10] = 1;
+        // queue_num_max
+        regs[0x34] = 16;
+        // Capacity in sectors
+        regs[0x100] = 8;
+        let addr = regs.as_mut_ptr() as usize;
+
+        let mut t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        let mut d = VirtioBlockDevice::new(&mut t);
+        d.init().unwrap();
+        assert_eq!(d.get_capacity(), 8);
+
+        let disk = (0..8u8).flat_map(|s| [s; 512]).collect();
+        let device = serve(addr, disk, 2);
+        let mut data = SectorBuf::new();
+        for sector in [3, 6] {
+            d.read(sector, data.as_mut_bytes()).unwrap();
+            assert_eq!(data.as_bytes(), &[sector as u8; 512]);
+        }
+        device.join().unwrap();
+        assert_eq!(d.stats().completed, 2);
+    }
+
+    #[test]
+    fn test_register_layout() {
+        use super::Registers;
+
+        assert_eq!(offset_of!(Registers, device_features), 0x010);
+        assert_eq!(offset_of!(Registers, driver_features), 0x020);
+        assert_eq!(offset_of!(Registers, queue_sel), 0x030);
+        assert_eq!(offset_of!(Registers, queue_ready), 0x044);
+        assert_eq!(offset_of!(Registers, queue_notify), 0x050);
+        assert_eq!(offset_of!(Registers, interrupt_status), 0x060);
+        assert_eq!(offset_of!(Registers, status), 0x070);
+        assert_eq!(offset_of!(Registers, queue_desc_low), 0x080);
+        assert_eq!(offset_of!(Registers, queue_driver_low), 0x090);
+        assert_eq!(offset_of!(Registers, queue_device_high), 0x0a4);
+        assert_eq!(offset_of!(Registers, config_generation), 0x0fc);
+        assert_eq!(super::CONFIG, 0x100);
+    }
+
+    #[test]
+    fn test_queue_address_split() {
+        let mut regs = registers(2, 2);
+        let t = VirtioMmioTransport::new(MemoryRegion::from_bytes(&mut regs));
+        t.set_descriptors_address(0x1234_5678_9abc_def0);
+
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(regs[offset..offset + 4].try_into().unwrap());
+        assert_eq!(read_u32(0x80), 0x9abc_def0);
+        assert_eq!(read_u32(0x84), 0x1234_5678);
+    }
+}